@@ -0,0 +1,313 @@
+use crate::bundle::{self, Bundle, BundleManifest};
+use flate2::read::GzDecoder;
+use std::io::{Cursor, Read};
+use thiserror::Error;
+use zip::ZipArchive;
+
+/// Largest firmware file we're willing to load into memory, after decompression. Bose's firmware
+/// images are a few megabytes at most.
+pub const MAX_FIRMWARE_SIZE: u64 = 64 * 1024 * 1024;
+
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+/// An empty zip archive is just an end of central directory record.
+const EMPTY_ZIP_MAGIC: &[u8] = b"PK\x05\x06";
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+/// What a firmware file turned out to contain.
+#[derive(Debug, Clone)]
+pub enum Contents {
+    /// A single firmware file, to be parsed as a DFU file.
+    Single(Vec<u8>),
+    /// A multi-model bundle, from which the file for a particular device has to be picked.
+    Bundle(Bundle),
+}
+
+/// Read a firmware file into memory from a source that may not be seekable, such as a pipe. If the
+/// data is gzip-compressed, it's decompressed. If it's a zip or tar archive, the one `.dfu` file
+/// inside it is extracted, unless the archive is a bundle (one with a [bundle::MANIFEST_FILE]), in
+/// which case every file the bundle's manifest lists is. Anything else is returned as is, to be
+/// parsed as a DFU file.
+///
+/// Fails if the firmware file (or the archive, for zip and tar files) is bigger than
+/// [MAX_FIRMWARE_SIZE].
+pub fn read_contents(reader: impl Read) -> Result<Contents, Error> {
+    read_contents_capped(reader, MAX_FIRMWARE_SIZE)
+}
+
+/// Like [read_contents], with `max_size` in place of [MAX_FIRMWARE_SIZE].
+fn read_contents_capped(reader: impl Read, max_size: u64) -> Result<Contents, Error> {
+    let mut data = read_capped(reader, max_size)?;
+    if data.starts_with(GZIP_MAGIC) {
+        data = read_capped(GzDecoder::new(&data[..]), max_size)?;
+    }
+
+    let mut archive = if data.starts_with(ZIP_MAGIC) || data.starts_with(EMPTY_ZIP_MAGIC) {
+        Archive::Zip(ZipArchive::new(Cursor::new(data))?)
+    } else if data.get(TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + TAR_MAGIC.len()) == Some(TAR_MAGIC) {
+        Archive::Tar(read_tar(&data)?)
+    } else {
+        return Ok(Contents::Single(data));
+    };
+
+    let Some(manifest) = archive.read(bundle::MANIFEST_FILE, max_size)? else {
+        return Ok(Contents::Single(archive.extract_dfu(max_size)?));
+    };
+    let manifest = String::from_utf8(manifest).map_err(|_| Error::ManifestNotText)?;
+    let manifest = BundleManifest::parse(&manifest)?;
+
+    let mut members = vec![];
+    for entry in manifest.entries {
+        let Some(data) = archive.read(&entry.file, max_size)? else {
+            return Err(bundle::Error::MissingFile(entry.file).into());
+        };
+        members.push((entry, data));
+    }
+    Ok(Contents::Bundle(Bundle::new(members)?))
+}
+
+/// Read a firmware file into memory like [read_contents], but fail if it's a bundle.
+pub fn read_firmware(reader: impl Read) -> Result<Vec<u8>, Error> {
+    match read_contents(reader)? {
+        Contents::Single(data) => Ok(data),
+        Contents::Bundle(_) => Err(Error::IsBundle),
+    }
+}
+
+/// Read all of `reader`, failing if it holds more than `max_size` bytes.
+fn read_capped(reader: impl Read, max_size: u64) -> Result<Vec<u8>, Error> {
+    let mut data = vec![];
+    reader.take(max_size + 1).read_to_end(&mut data)?;
+
+    if data.len() as u64 > max_size {
+        return Err(Error::TooBig);
+    }
+    Ok(data)
+}
+
+/// Read every regular file in a tar archive. The archive is already in memory and no bigger than
+/// the size cap, so its files can't be either.
+fn read_tar(data: &[u8]) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let mut files = vec![];
+    for entry in tar::Archive::new(data).entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let mut contents = vec![];
+        entry.read_to_end(&mut contents)?;
+        files.push((name, contents));
+    }
+    Ok(files)
+}
+
+/// A zip or tar archive, either of which may hold a firmware file or a bundle.
+enum Archive {
+    Zip(ZipArchive<Cursor<Vec<u8>>>),
+    Tar(Vec<(String, Vec<u8>)>),
+}
+
+impl Archive {
+    fn file_names(&self) -> Vec<&str> {
+        match self {
+            Archive::Zip(zip) => zip.file_names().collect(),
+            Archive::Tar(files) => files.iter().map(|(name, _)| name.as_str()).collect(),
+        }
+    }
+
+    /// Read the file with the given name, if the archive has one, failing if it's bigger than
+    /// `max_size`. A leading `./`, as produced by `tar -C dir .`, is ignored.
+    fn read(&mut self, name: &str, max_size: u64) -> Result<Option<Vec<u8>>, Error> {
+        let name = name.trim_start_matches("./");
+        let Some(stored) = self
+            .file_names()
+            .into_iter()
+            .find(|stored| stored.trim_start_matches("./") == name)
+            .map(str::to_owned)
+        else {
+            return Ok(None);
+        };
+
+        match self {
+            Archive::Zip(zip) => read_capped(zip.by_name(&stored)?, max_size).map(Some),
+            Archive::Tar(files) => Ok(files
+                .iter_mut()
+                .find(|(n, _)| *n == stored)
+                .map(|(_, data)| std::mem::take(data))),
+        }
+    }
+
+    /// Extract the only `.dfu` file from the archive.
+    fn extract_dfu(&mut self, max_size: u64) -> Result<Vec<u8>, Error> {
+        let names: Vec<_> = self
+            .file_names()
+            .into_iter()
+            .filter(|name| name.to_ascii_lowercase().ends_with(".dfu"))
+            .map(str::to_owned)
+            .collect();
+        let name = match &names[..] {
+            [name] => name,
+            [] => return Err(Error::NoDfuInArchive),
+            _ => return Err(Error::SeveralDfusInArchive(names.join(", "))),
+        };
+
+        Ok(self
+            .read(name, max_size)?
+            .expect("file should be in archive"))
+    }
+}
+
+/// Errors that can happen while reading a possibly-compressed firmware file.
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("firmware file is bigger than {} MiB", MAX_FIRMWARE_SIZE / 1024 / 1024)]
+    TooBig,
+
+    #[error("invalid zip archive")]
+    ZipError(#[from] zip::result::ZipError),
+
+    #[error("archive contains no .dfu file")]
+    NoDfuInArchive,
+
+    #[error(
+        "archive contains more than one .dfu file ({0}); to hold firmware for several models, \
+        it needs a {manifest}",
+        manifest = bundle::MANIFEST_FILE
+    )]
+    SeveralDfusInArchive(String),
+
+    #[error("{} is not a text file", bundle::MANIFEST_FILE)]
+    ManifestNotText,
+
+    #[error("invalid firmware bundle")]
+    BundleError(#[from] bundle::Error),
+
+    #[error("file is a multi-model firmware bundle, which only `download` and `update` can use")]
+    IsBundle,
+
+    #[error("I/O error")]
+    IoError(#[from] std::io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(vec![]));
+        for (name, data) in files {
+            zip.start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn tar(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut tar = tar::Builder::new(vec![]);
+        for (name, data) in files {
+            let mut header = tar::Header::new_ustar();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, name, *data).unwrap();
+        }
+        tar.into_inner().unwrap()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut gz = GzEncoder::new(vec![], Compression::default());
+        gz.write_all(data).unwrap();
+        gz.finish().unwrap()
+    }
+
+    fn single(result: Result<Contents, Error>) -> Vec<u8> {
+        match result.unwrap() {
+            Contents::Single(data) => data,
+            Contents::Bundle(_) => panic!("expected a single file, got a bundle"),
+        }
+    }
+
+    #[test]
+    fn extracts_the_only_dfu_file() {
+        let dfu = b"not really a DFU file";
+        assert_eq!(single(read_contents(&dfu[..])), dfu);
+        assert_eq!(single(read_contents(&gzip(dfu)[..])), dfu);
+
+        let files: &[(&str, &[u8])] = &[("README.txt", b"hello"), ("fw/LANDO.DFU", dfu)];
+        assert_eq!(single(read_contents(&zip(files)[..])), dfu);
+        assert_eq!(single(read_contents(&tar(files)[..])), dfu);
+        assert_eq!(single(read_contents(&gzip(&tar(files))[..])), dfu);
+    }
+
+    #[test]
+    fn empty_archives_are_refused() {
+        assert!(matches!(
+            read_contents(&zip(&[])[..]),
+            Err(Error::NoDfuInArchive)
+        ));
+        assert!(matches!(
+            read_contents(&tar(&[("notes.txt", b"no firmware here")])[..]),
+            Err(Error::NoDfuInArchive)
+        ));
+        // An empty file isn't an archive, so it's left for the DFU parser to reject.
+        assert!(single(read_contents(&b""[..])).is_empty());
+    }
+
+    #[test]
+    fn several_dfu_files_need_a_manifest() {
+        let files: &[(&str, &[u8])] = &[("a.dfu", b"a"), ("b.dfu", b"b")];
+        match read_contents(&zip(files)[..]) {
+            Err(Error::SeveralDfusInArchive(names)) => assert_eq!(names, "a.dfu, b.dfu"),
+            other => panic!("expected several .dfu files, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn bundle_problems_are_reported() {
+        let manifest = b"[[firmware]]\nfile = \"a.dfu\"\nmodel = \"A\"\nusb_id = \"05a7:400d\"\n";
+        assert!(matches!(
+            read_contents(&zip(&[(bundle::MANIFEST_FILE, manifest)])[..]),
+            Err(Error::BundleError(bundle::Error::MissingFile(_)))
+        ));
+        assert!(matches!(
+            read_contents(&tar(&[(bundle::MANIFEST_FILE, b"\xff\xfe")])[..]),
+            Err(Error::ManifestNotText)
+        ));
+    }
+
+    #[test]
+    fn oversized_files_are_refused() {
+        const CAP: u64 = 1024;
+        let big = vec![0u8; CAP as usize + 1];
+        assert_eq!(
+            single(read_contents_capped(&big[..CAP as usize], CAP)).len(),
+            CAP as usize
+        );
+
+        assert!(matches!(
+            read_contents_capped(&big[..], CAP),
+            Err(Error::TooBig)
+        ));
+        // Compressed data is small enough, but not once decompressed.
+        let compressed = gzip(&big);
+        assert!(compressed.len() < CAP as usize);
+        assert!(matches!(
+            read_contents_capped(&compressed[..], CAP),
+            Err(Error::TooBig)
+        ));
+        let zipped = zip(&[("big.dfu", &big)]);
+        assert!(zipped.len() < CAP as usize);
+        assert!(matches!(
+            read_contents_capped(&zipped[..], CAP),
+            Err(Error::TooBig)
+        ));
+    }
+}
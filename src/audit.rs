@@ -0,0 +1,425 @@
+use crate::device_ids::UsbId;
+use crate::firmware::sha256_hex;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use thiserror::Error;
+
+/// [AuditEntry::error_variant] for errors that don't come from this crate.
+pub const OTHER_ERROR_VARIANT: &str = "other";
+
+/// Tag at the start of the data each entry's hash covers. Any change to [AuditEntry::hashed_data]
+/// needs a new tag, since entries hashed the old way would otherwise fail verification.
+const HASH_FORMAT: &str = "bose-dfu audit entry v1";
+
+/// Hash that the first entry in a log chains from.
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// An append-only log of operations that change a device's state, kept as a TOML file containing
+/// a list of `[[entry]]` tables, oldest first.
+///
+/// Each entry records the hash of the entry before it and a hash of its own contents, so editing,
+/// removing, or reordering any entry but the last ones is detected by [AuditLog::verify].
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+/// One operation on one or more devices.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuditEntry {
+    /// When the operation finished, in RFC 3339 format.
+    pub timestamp: String,
+    /// Name of the user on this host who ran the operation.
+    pub user: String,
+    /// The bose-dfu subcommand that performed the operation.
+    pub command: String,
+    /// SHA-256 hash of the firmware file written, if any, as a lowercase hex string.
+    pub file_sha256: Option<String>,
+    pub result: Outcome,
+    /// Which error the operation failed with, as named by
+    /// [ErrorVariant](crate::error_variant::ErrorVariant), or [OTHER_ERROR_VARIANT] if it's not
+    /// one of this crate's.
+    pub error_variant: Option<String>,
+    /// The full error message, if the operation failed.
+    pub error: Option<String>,
+    /// TAP commands sent during a TAP session.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tap_commands: Vec<String>,
+    /// Hash of the previous entry.
+    pub prev_hash: String,
+    /// Hash of this entry's contents, including `prev_hash`.
+    pub hash: String,
+    /// Devices the operation was performed on.
+    pub devices: Vec<AuditDevice>,
+}
+
+/// A device an audited operation was performed on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuditDevice {
+    pub usb_id: UsbId,
+    /// USB serial number, if the device reported one.
+    pub serial: Option<String>,
+}
+
+/// Whether an audited operation succeeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Ok,
+    Failed,
+}
+
+/// On-disk format of the log.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LogFile {
+    #[serde(default, rename = "entry")]
+    entries: Vec<AuditEntry>,
+}
+
+impl AuditEntry {
+    /// Describe an operation that just finished. The hashes are filled in by [AuditLog::append].
+    pub fn new(user: &str, command: &str, devices: Vec<AuditDevice>, outcome: Outcome) -> Self {
+        Self {
+            timestamp: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            user: user.to_owned(),
+            command: command.to_owned(),
+            file_sha256: None,
+            result: outcome,
+            error_variant: None,
+            error: None,
+            tap_commands: vec![],
+            prev_hash: String::new(),
+            hash: String::new(),
+            devices,
+        }
+    }
+
+    /// Hash everything in the entry except `hash` itself.
+    fn compute_hash(&self) -> String {
+        sha256_hex(&self.hashed_data())
+    }
+
+    /// Encode every field but `hash` in a fixed order, independent of how the log file is
+    /// formatted. Each string is prefixed with its length, optional fields with whether they're
+    /// present, and lists with their length, so no two different entries encode the same way.
+    fn hashed_data(&self) -> Vec<u8> {
+        let mut data = vec![];
+        put_str(&mut data, HASH_FORMAT);
+        put_str(&mut data, &self.timestamp);
+        put_str(&mut data, &self.user);
+        put_str(&mut data, &self.command);
+        put_optional(&mut data, self.file_sha256.as_deref());
+        put_str(
+            &mut data,
+            match self.result {
+                Outcome::Ok => "ok",
+                Outcome::Failed => "failed",
+            },
+        );
+        put_optional(&mut data, self.error_variant.as_deref());
+        put_optional(&mut data, self.error.as_deref());
+        put_len(&mut data, self.tap_commands.len());
+        for command in &self.tap_commands {
+            put_str(&mut data, command);
+        }
+        put_str(&mut data, &self.prev_hash);
+        put_len(&mut data, self.devices.len());
+        for device in &self.devices {
+            data.extend(device.usb_id.vid.to_be_bytes());
+            data.extend(device.usb_id.pid.to_be_bytes());
+            put_optional(&mut data, device.serial.as_deref());
+        }
+        data
+    }
+}
+
+fn put_len(data: &mut Vec<u8>, len: usize) {
+    data.extend((len as u64).to_be_bytes());
+}
+
+fn put_str(data: &mut Vec<u8>, value: &str) {
+    put_len(data, value.len());
+    data.extend(value.as_bytes());
+}
+
+fn put_optional(data: &mut Vec<u8>, value: Option<&str>) {
+    match value {
+        None => data.push(0),
+        Some(value) => {
+            data.push(1);
+            put_str(data, value);
+        }
+    }
+}
+
+impl AuditLog {
+    /// Use the log at `path`, which is created when the first entry is added.
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_owned(),
+        }
+    }
+
+    /// The file the log is kept in.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read every entry in the log, without checking the hashes. A missing log has no entries.
+    pub fn entries(&self) -> Result<Vec<AuditEntry>, Error> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(source) => return Err(self.io_err(source)),
+        };
+        // Don't read an entry that's halfway through being appended.
+        file.lock_shared().map_err(|e| self.io_err(e))?;
+        self.read_entries(&mut file)
+    }
+
+    /// Chain `entry` onto the last one in the log and append it to the file. Refuses to add to a
+    /// log that can't be parsed, since the new entry couldn't be chained onto the ones before it.
+    pub fn append(&self, mut entry: AuditEntry) -> Result<(), Error> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| self.io_err(e))?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| self.io_err(e))?;
+        // Hold the lock until the entry is written, so two processes appending at once can't both
+        // chain from the same entry.
+        file.lock().map_err(|e| self.io_err(e))?;
+
+        let last = self.read_entries(&mut file)?.pop();
+        entry.prev_hash = last.map_or(GENESIS_HASH.to_owned(), |e| e.hash);
+        entry.hash = entry.compute_hash();
+
+        let log = LogFile {
+            entries: vec![entry],
+        };
+        let text = toml::to_string(&log).expect("audit entry should always serialize");
+
+        // Only ever append, so existing entries can't be lost even if writing is interrupted.
+        file.write_all(format!("{text}\n").as_bytes())
+            .and_then(|()| file.sync_all())
+            .map_err(|e| self.io_err(e))
+    }
+
+    /// Check that every entry's hash matches its contents and that each entry chains from the one
+    /// before it, returning the number of entries. Can't detect entries removed from the end.
+    pub fn verify(&self) -> Result<usize, Error> {
+        let entries = self.entries()?;
+
+        let mut prev_hash = GENESIS_HASH;
+        for (i, entry) in entries.iter().enumerate() {
+            if entry.prev_hash != prev_hash {
+                return Err(Error::BrokenChain {
+                    index: i + 1,
+                    timestamp: entry.timestamp.clone(),
+                });
+            }
+            if entry.hash != entry.compute_hash() {
+                return Err(Error::Modified {
+                    index: i + 1,
+                    timestamp: entry.timestamp.clone(),
+                });
+            }
+            prev_hash = &entry.hash;
+        }
+
+        Ok(entries.len())
+    }
+
+    fn read_entries(&self, file: &mut File) -> Result<Vec<AuditEntry>, Error> {
+        let mut text = String::new();
+        file.read_to_string(&mut text).map_err(|e| self.io_err(e))?;
+
+        let log: LogFile = toml::from_str(&text).map_err(|source| Error::ParseError {
+            source,
+            path: self.path.clone(),
+        })?;
+        Ok(log.entries)
+    }
+
+    fn io_err(&self, source: std::io::Error) -> Error {
+        Error::IoError {
+            source,
+            path: self.path.clone(),
+        }
+    }
+}
+
+/// Errors that can happen while writing or checking an audit log.
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("failed to access audit log {}", .path.display())]
+    IoError {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+
+    #[error("invalid audit log {}", .path.display())]
+    ParseError {
+        source: toml::de::Error,
+        path: PathBuf,
+    },
+
+    #[error("audit log entry {index} ({timestamp}) has been modified")]
+    Modified { index: usize, timestamp: String },
+
+    #[error(
+        "audit log entry {index} ({timestamp}) doesn't follow the entry before it; \
+        entries have been removed, inserted, or reordered"
+    )]
+    BrokenChain { index: usize, timestamp: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_ids::UsbId;
+
+    /// Create a log with `count` entries in a fresh directory.
+    fn log_with_entries(name: &str, count: usize) -> AuditLog {
+        let dir =
+            std::env::temp_dir().join(format!("bose-dfu-audit-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let log = AuditLog::new(&dir.join("audit.toml"));
+        for i in 0..count {
+            log.append(entry(&format!("user{i}"))).unwrap();
+        }
+        log
+    }
+
+    fn entry(user: &str) -> AuditEntry {
+        let device = AuditDevice {
+            usb_id: UsbId {
+                vid: 0x05a7,
+                pid: 0x40fe,
+            },
+            serial: Some("0123456789".to_owned()),
+        };
+        AuditEntry::new(user, "update", vec![device], Outcome::Ok)
+    }
+
+    /// Replace the log's contents with `entries`, as someone editing it by hand might.
+    fn rewrite(log: &AuditLog, entries: Vec<AuditEntry>) {
+        let text = toml::to_string(&LogFile { entries }).unwrap();
+        std::fs::write(log.path(), text).unwrap();
+    }
+
+    #[test]
+    fn hash_is_stable() {
+        let mut entry = entry("alice");
+        entry.timestamp = "2026-01-02T03:04:05Z".to_owned();
+        entry.file_sha256 = Some("ab".repeat(32));
+        entry.prev_hash = GENESIS_HASH.to_owned();
+        // Changing this means every existing log fails verification; change HASH_FORMAT instead.
+        assert_eq!(
+            entry.compute_hash(),
+            "0e5cef2d6b239d40dd2bb2f955f44ce4c3daa8a06efe6967ff6541cebe661411"
+        );
+    }
+
+    #[test]
+    fn hash_separates_fields() {
+        let mut first = entry("ab");
+        first.command = "c".to_owned();
+        let mut second = first.clone();
+        second.user = "a".to_owned();
+        second.command = "bc".to_owned();
+        assert_ne!(first.compute_hash(), second.compute_hash());
+
+        let mut third = first.clone();
+        third.error = Some(String::new());
+        assert_ne!(first.compute_hash(), third.compute_hash());
+    }
+
+    #[test]
+    fn appends_chain() {
+        let log = log_with_entries("chain", 2);
+        let entries = log.entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].prev_hash, GENESIS_HASH);
+        assert_eq!(entries[1].prev_hash, entries[0].hash);
+        assert_eq!(log.verify().unwrap(), 2);
+    }
+
+    #[test]
+    fn concurrent_appends_chain() {
+        let log = log_with_entries("concurrent", 0);
+        std::thread::scope(|scope| {
+            for i in 0..8 {
+                let log = &log;
+                scope.spawn(move || {
+                    for j in 0..5 {
+                        log.append(entry(&format!("user{i}-{j}"))).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(log.verify().unwrap(), 40);
+    }
+
+    #[test]
+    fn detects_edited_entry() {
+        let log = log_with_entries("edited", 3);
+        let mut entries = log.entries().unwrap();
+        entries[1].user = "someone else".to_owned();
+        rewrite(&log, entries);
+        assert!(matches!(
+            log.verify(),
+            Err(Error::Modified { index: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn detects_removed_entry() {
+        let log = log_with_entries("removed", 3);
+        let mut entries = log.entries().unwrap();
+        entries.remove(1);
+        rewrite(&log, entries);
+        assert!(matches!(
+            log.verify(),
+            Err(Error::BrokenChain { index: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn detects_reordered_entries() {
+        let log = log_with_entries("reordered", 3);
+        let mut entries = log.entries().unwrap();
+        entries.swap(1, 2);
+        rewrite(&log, entries);
+        assert!(matches!(
+            log.verify(),
+            Err(Error::BrokenChain { index: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn refuses_to_append_to_invalid_log() {
+        let log = log_with_entries("invalid", 1);
+        let mut text = std::fs::read_to_string(log.path()).unwrap();
+        // As if an earlier append was cut off partway through.
+        text.push_str("[[entry]]\ntimestamp = \"2026-");
+        std::fs::write(log.path(), &text).unwrap();
+
+        let result = log.append(entry("user"));
+        assert!(
+            matches!(result, Err(Error::ParseError { .. })),
+            "{result:?}"
+        );
+        assert_eq!(std::fs::read_to_string(log.path()).unwrap(), text);
+    }
+}
@@ -0,0 +1,133 @@
+use crate::device_ids::UsbId;
+use crate::firmware::sha256_hex;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use thiserror::Error;
+
+/// A directory of firmware images read back from devices before they were updated, with one
+/// subdirectory per device, named after its USB serial number. Each image is saved as a `.bin`
+/// file next to a `.toml` file of the same name holding its [BackupInfo].
+///
+/// Bose devices don't read back exactly what was written to them, so backups can't be written
+/// back to a device. They're only kept for analysis.
+#[derive(Debug)]
+pub struct BackupStore {
+    dir: PathBuf,
+}
+
+/// What's known about a backed-up image.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackupInfo {
+    /// USB serial number of the device the image was read from.
+    pub serial: Option<String>,
+    /// DFU-mode USB ID of the device.
+    pub usb_id: UsbId,
+    /// Firmware version the device reported before entering DFU mode, if it was asked.
+    pub firmware_version: Option<String>,
+    /// When the image was read, in RFC 3339 format.
+    pub timestamp: String,
+    /// SHA-256 hash of the image, as a lowercase hex string.
+    pub sha256: String,
+    pub size: u64,
+}
+
+impl BackupInfo {
+    /// Describe `image`, just read from the device with the given details.
+    pub fn new(
+        image: &[u8],
+        serial: Option<&str>,
+        usb_id: UsbId,
+        firmware_version: Option<&str>,
+    ) -> Self {
+        Self {
+            serial: serial.map(str::to_owned),
+            usb_id,
+            firmware_version: firmware_version.map(str::to_owned),
+            timestamp: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            sha256: sha256_hex(image),
+            size: image.len() as u64,
+        }
+    }
+}
+
+impl BackupStore {
+    /// Where backups are saved by default: `bose-dfu/backups` inside the OS's data directory.
+    pub fn default_dir() -> Option<PathBuf> {
+        dirs::data_dir().map(|d| d.join("bose-dfu").join("backups"))
+    }
+
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_owned(),
+        }
+    }
+
+    /// The directory backups are saved in.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Save `image` and `info` in the directory for the device `info` describes, named after
+    /// `info.timestamp`. Returns the path of the saved image.
+    pub fn save(&self, image: &[u8], info: &BackupInfo) -> Result<PathBuf, Error> {
+        let dir = self.dir.join(device_dir_name(info.serial.as_deref()));
+        let io_err = |path: &Path| {
+            let path = path.to_owned();
+            move |source| Error::IoError { source, path }
+        };
+        std::fs::create_dir_all(&dir).map_err(io_err(&dir))?;
+
+        // Timestamps only have one-second resolution, and colons aren't allowed in file names on
+        // Windows.
+        let stem = info.timestamp.replace(':', "-");
+        let mut n = 1;
+        let (image_path, mut image_file) = loop {
+            let path = match n {
+                1 => dir.join(format!("{stem}.bin")),
+                n => dir.join(format!("{stem}-{n}.bin")),
+            };
+            match std::fs::File::create_new(&path) {
+                Ok(file) => break (path, file),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => n += 1,
+                Err(source) => return Err(Error::IoError { source, path }),
+            }
+        };
+        image_file.write_all(image).map_err(io_err(&image_path))?;
+
+        let info_path = image_path.with_extension("toml");
+        let text = toml::to_string(info).expect("backup info should always serialize");
+        std::fs::write(&info_path, text).map_err(io_err(&info_path))?;
+
+        Ok(image_path)
+    }
+}
+
+/// Name of the subdirectory for a device's backups, made safe to use as a file name.
+fn device_dir_name(serial: Option<&str>) -> String {
+    match serial {
+        Some(serial) if !serial.is_empty() => serial
+            .chars()
+            .map(
+                |c| match c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                    true => c,
+                    false => '_',
+                },
+            )
+            .collect(),
+        _ => "no-serial".to_owned(),
+    }
+}
+
+/// Errors that can happen while saving a backup.
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("failed to write {}", .path.display())]
+    IoError {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+}
@@ -0,0 +1,276 @@
+use crate::device_ids::UsbId;
+use crate::dfu_file::{self, DfuFile, SuffixInfo};
+use serde::Deserialize;
+use thiserror::Error;
+
+/// Name of the manifest file at the top level of a bundle archive.
+pub const MANIFEST_FILE: &str = "bundle.toml";
+
+/// The manifest of a multi-model firmware bundle: a zip or tar archive holding one `.dfu` file per
+/// model in a product line, plus a [MANIFEST_FILE] containing a list of `[[firmware]]` tables that
+/// say which file is for which model.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BundleManifest {
+    #[serde(default, rename = "firmware")]
+    pub entries: Vec<BundleEntry>,
+}
+
+/// One file listed in a bundle's manifest.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BundleEntry {
+    /// Path of the file inside the archive.
+    pub file: String,
+    /// Model string reported by the device's normal firmware (the TAP `pl` field).
+    pub model: String,
+    /// DFU-mode USB ID of the device the file is for.
+    pub usb_id: UsbId,
+}
+
+/// A bundle whose files have all been read and checked against the manifest.
+#[derive(Debug, Clone)]
+pub struct Bundle {
+    pub members: Vec<BundleMember>,
+}
+
+/// One file in a bundle, along with what the manifest says about it.
+#[derive(Debug, Clone)]
+pub struct BundleMember {
+    pub entry: BundleEntry,
+    pub suffix: SuffixInfo,
+    pub data: Vec<u8>,
+}
+
+impl BundleManifest {
+    /// Parse the text of a bundle's [MANIFEST_FILE].
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let manifest: BundleManifest = toml::from_str(text)?;
+        if manifest.entries.is_empty() {
+            return Err(Error::Empty);
+        }
+        Ok(manifest)
+    }
+}
+
+impl Bundle {
+    /// Build a bundle from each manifest entry and the contents of the file it names. Fails if any
+    /// file isn't a DFU file with a valid CRC, or if its suffix contradicts the manifest's USB ID.
+    pub fn new(members: Vec<(BundleEntry, Vec<u8>)>) -> Result<Self, Error> {
+        let members = members
+            .into_iter()
+            .map(|(entry, data)| {
+                let bad_file = |source| Error::BadFile {
+                    source,
+                    file: entry.file.clone(),
+                };
+                let file = DfuFile::from_bytes(&data).map_err(bad_file)?;
+                file.ensure_valid_crc().map_err(|e| bad_file(e.into()))?;
+                if !file.matches_device(entry.usb_id) {
+                    let suffix = file.suffix();
+                    return Err(Error::WrongDevice {
+                        file: entry.file.clone(),
+                        file_id: format!("{:04x}:{:04x}", suffix.vendor_id, suffix.product_id),
+                        usb_id: entry.usb_id,
+                    });
+                }
+
+                let suffix = file.suffix().clone();
+                Ok(BundleMember {
+                    entry,
+                    suffix,
+                    data,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { members })
+    }
+
+    /// Pick the file for a device with the given DFU-mode USB ID and, if known, model string. The
+    /// file's suffix must match the USB ID. Fails unless exactly one file matches: if several do
+    /// and the model isn't known, or the bundle lists more than one file for a model, there's no
+    /// telling which is right.
+    pub fn select(&self, dfu_id: UsbId, model: Option<&str>) -> Result<&BundleMember, Error> {
+        let matches: Vec<_> = self
+            .members
+            .iter()
+            .filter(|m| {
+                m.entry.usb_id == dfu_id
+                    && m.suffix.vendor_id.matches(dfu_id.vid)
+                    && m.suffix.product_id.matches(dfu_id.pid)
+                    && model.is_none_or(|model| m.entry.model == model)
+            })
+            .collect();
+
+        match &matches[..] {
+            [member] => Ok(member),
+            [] => Err(Error::NoMatch {
+                usb_id: dfu_id,
+                model: model.map(str::to_owned),
+            }),
+            _ => Err(Error::Ambiguous(
+                matches
+                    .iter()
+                    .map(|m| format!("{} ({})", m.entry.file, m.entry.model))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            )),
+        }
+    }
+}
+
+/// Errors that can happen while reading a bundle or picking a file from it.
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("invalid bundle manifest")]
+    ParseError(#[from] toml::de::Error),
+
+    #[error("bundle manifest lists no firmware files")]
+    Empty,
+
+    #[error("bundle doesn't contain {0}, which its manifest lists")]
+    MissingFile(String),
+
+    #[error("{file} in bundle is not a valid firmware file")]
+    BadFile {
+        source: dfu_file::Error,
+        file: String,
+    },
+
+    #[error("{file} in bundle is for USB ID {file_id}, but the manifest says {usb_id}")]
+    WrongDevice {
+        file: String,
+        file_id: String,
+        usb_id: UsbId,
+    },
+
+    #[error(
+        "bundle has no firmware for USB ID {usb_id}{}",
+        .model.as_ref().map(|m| format!(" and model {m:?}")).unwrap_or_default()
+    )]
+    NoMatch {
+        usb_id: UsbId,
+        model: Option<String>,
+    },
+
+    #[error("bundle has several files that could be for this device: {0}")]
+    Ambiguous(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dfu_file::OptionalId;
+
+    const COLOR: UsbId = UsbId {
+        vid: 0x05a7,
+        pid: 0x400d,
+    };
+    const QC35: UsbId = UsbId {
+        vid: 0x05a7,
+        pid: 0x4020,
+    };
+
+    /// A DFU file for `id` with a short payload.
+    fn dfu(id: UsbId) -> Vec<u8> {
+        let suffix = SuffixInfo::new(
+            OptionalId(Some(id.vid)),
+            OptionalId(Some(id.pid)),
+            OptionalId(None),
+        );
+        let mut out = vec![];
+        dfu_file::write(&mut out, b"firmware", &suffix).unwrap();
+        out
+    }
+
+    fn member(file: &str, model: &str, usb_id: UsbId) -> (BundleEntry, Vec<u8>) {
+        let entry = BundleEntry {
+            file: file.to_owned(),
+            model: model.to_owned(),
+            usb_id,
+        };
+        (entry, dfu(usb_id))
+    }
+
+    fn selected(result: Result<&BundleMember, Error>) -> &str {
+        &result.unwrap().entry.file
+    }
+
+    #[test]
+    fn selects_by_usb_id_and_model() {
+        let bundle = Bundle::new(vec![
+            member("a.dfu", "Model A", COLOR),
+            member("b.dfu", "Model B", COLOR),
+            member("qc35.dfu", "Bose QC35 II", QC35),
+        ])
+        .unwrap();
+
+        assert_eq!(selected(bundle.select(QC35, None)), "qc35.dfu");
+        assert_eq!(selected(bundle.select(COLOR, Some("Model B"))), "b.dfu");
+        assert!(matches!(
+            bundle.select(QC35, Some("Model A")),
+            Err(Error::NoMatch { model: Some(_), .. })
+        ));
+        let other = UsbId { vid: 1, pid: 2 };
+        assert!(matches!(
+            bundle.select(other, None),
+            Err(Error::NoMatch { model: None, .. })
+        ));
+    }
+
+    #[test]
+    fn ambiguous_matches_are_refused() {
+        let bundle = Bundle::new(vec![
+            member("a.dfu", "Model A", COLOR),
+            member("b.dfu", "Model B", COLOR),
+            member("a2.dfu", "Model A", COLOR),
+        ])
+        .unwrap();
+
+        // Without the model, every file for the USB ID could be right.
+        match bundle.select(COLOR, None) {
+            Err(Error::Ambiguous(files)) => {
+                assert_eq!(files, "a.dfu (Model A), b.dfu (Model B), a2.dfu (Model A)")
+            }
+            other => panic!("expected ambiguous match, got {other:?}"),
+        }
+        // Even with it, the bundle lists two files for the same model.
+        match bundle.select(COLOR, Some("Model A")) {
+            Err(Error::Ambiguous(files)) => assert_eq!(files, "a.dfu (Model A), a2.dfu (Model A)"),
+            other => panic!("expected ambiguous match, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn mismatched_or_corrupt_files_are_refused() {
+        let (entry, _) = member("a.dfu", "Model A", COLOR);
+        assert!(matches!(
+            Bundle::new(vec![(entry.clone(), dfu(QC35))]),
+            Err(Error::WrongDevice { .. })
+        ));
+
+        let mut data = dfu(COLOR);
+        data[0] ^= 1;
+        assert!(matches!(
+            Bundle::new(vec![(entry, data)]),
+            Err(Error::BadFile { .. })
+        ));
+    }
+
+    #[test]
+    fn manifest_must_list_files() {
+        assert!(matches!(BundleManifest::parse(""), Err(Error::Empty)));
+        assert!(matches!(
+            BundleManifest::parse("[[firmware]]\nfile = \"a.dfu\"\n"),
+            Err(Error::ParseError(_))
+        ));
+
+        let manifest = BundleManifest::parse(
+            "[[firmware]]\nfile = \"a.dfu\"\nmodel = \"Model A\"\nusb_id = \"05a7:400d\"\n",
+        )
+        .unwrap();
+        assert_eq!(manifest.entries[0].usb_id, COLOR);
+    }
+}
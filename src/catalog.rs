@@ -0,0 +1,269 @@
+use crate::device_ids::{BOSE_VID, UsbId, find_device_ids};
+use roxmltree::{Document, Node};
+use thiserror::Error;
+
+/// The contents of a `lookup.xml` file, which lists every device Bose's download server has
+/// firmware for. Each device has a `<PRODUCT>` element whose `USBPID` attribute holds the device's
+/// product ID in DFU mode and whose `PATH` attribute holds the URL of the device's `index.xml`.
+///
+/// Since Bose doesn't document this format or [Index]'s, parsing is lenient: element and attribute
+/// names are matched case-insensitively, and unrecognized elements are ignored.
+#[derive(Debug)]
+pub struct Lookup {
+    pub products: Vec<LookupProduct>,
+}
+
+/// One `<PRODUCT>` element from `lookup.xml`.
+#[derive(Debug)]
+pub struct LookupProduct {
+    /// USB product ID of the device in DFU mode.
+    pub dfu_pid: u16,
+    /// Where the device's `index.xml` lives, usually as an absolute URL.
+    pub index_url: String,
+}
+
+/// The contents of an `index.xml` file, which lives in a directory named for a device's codename
+/// alongside that device's firmware files. Each firmware file has an `<IMAGE>` element whose
+/// `FILENAME` attribute holds its name, optionally nested in a `<RELEASE>` element whose `REVISION`
+/// attribute holds its version.
+#[derive(Debug)]
+pub struct Index {
+    pub images: Vec<IndexImage>,
+}
+
+/// One `<IMAGE>` element from `index.xml`.
+#[derive(Debug)]
+pub struct IndexImage {
+    /// Name of the firmware file, relative to the directory holding `index.xml`.
+    pub filename: String,
+    /// Revision of the `<RELEASE>` the image belongs to, if any.
+    pub revision: Option<String>,
+}
+
+impl Lookup {
+    pub fn parse(xml: &str) -> Result<Self, Error> {
+        let doc = Document::parse(xml)?;
+
+        let products = elements(&doc, "PRODUCT")
+            .map(|node| {
+                let pid = required_attr(node, "USBPID")?;
+                Ok(LookupProduct {
+                    dfu_pid: u16::from_str_radix(pid.trim_start_matches("0x"), 16)
+                        .map_err(|_| Error::BadProductId(pid.to_owned()))?,
+                    index_url: required_attr(node, "PATH")?.to_owned(),
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(Self { products })
+    }
+
+    /// Find the product entry for a device, given its USB ID in either normal or DFU mode.
+    pub fn find(&self, id: UsbId) -> Option<&LookupProduct> {
+        let dfu_id = find_device_ids(id).map_or(id, |ids| ids.dfu_mode);
+        if dfu_id.vid != BOSE_VID {
+            return None;
+        }
+
+        self.products.iter().find(|p| p.dfu_pid == dfu_id.pid)
+    }
+}
+
+impl LookupProduct {
+    /// The path part of [LookupProduct::index_url], without a leading slash: that is, where
+    /// `index.xml` lives relative to the root of the server.
+    pub fn index_path(&self) -> &str {
+        let url = self.index_url.as_str();
+        let path = match url.split_once("://") {
+            Some((_, rest)) => rest.split_once('/').map_or("", |(_, path)| path),
+            None => url,
+        };
+        path.trim_start_matches('/')
+    }
+
+    /// The device's codename, which is the name of the directory holding its `index.xml`.
+    pub fn codename(&self) -> Option<&str> {
+        let mut segments = self.index_path().rsplit('/').skip(1);
+        segments.next().filter(|s| !s.is_empty())
+    }
+
+    /// Where the directory holding `index.xml`, and thus the device's firmware files, lives
+    /// relative to the root of the server.
+    pub fn directory(&self) -> &str {
+        let path = self.index_path();
+        path.rsplit_once('/').map_or("", |(dir, _)| dir)
+    }
+}
+
+impl Index {
+    pub fn parse(xml: &str) -> Result<Self, Error> {
+        let doc = Document::parse(xml)?;
+
+        let images = elements(&doc, "IMAGE")
+            .map(|node| {
+                let revision = node
+                    .ancestors()
+                    .find(|a| a.tag_name().name().eq_ignore_ascii_case("RELEASE"))
+                    .and_then(|release| attr(release, "REVISION"))
+                    .map(str::to_owned);
+
+                Ok(IndexImage {
+                    filename: required_attr(node, "FILENAME")?.to_owned(),
+                    revision,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(Self { images })
+    }
+
+    /// The image to install on a device. `index.xml` normally lists only the latest firmware, so
+    /// this is the first image listed.
+    pub fn latest(&self) -> Option<&IndexImage> {
+        self.images.first()
+    }
+}
+
+/// Iterate over all elements in `doc` with the given name, ignoring case.
+fn elements<'a, 'input: 'a>(
+    doc: &'a Document<'input>,
+    name: &'static str,
+) -> impl Iterator<Item = Node<'a, 'input>> {
+    doc.descendants()
+        .filter(move |n| n.is_element() && n.tag_name().name().eq_ignore_ascii_case(name))
+}
+
+/// Get the value of an attribute, ignoring case.
+fn attr<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    node.attributes()
+        .find(|a| a.name().eq_ignore_ascii_case(name))
+        .map(|a| a.value())
+}
+
+fn required_attr<'a>(node: Node<'a, '_>, name: &'static str) -> Result<&'a str, Error> {
+    attr(node, name).ok_or_else(|| Error::MissingAttribute {
+        element: node.tag_name().name().to_owned(),
+        attribute: name,
+    })
+}
+
+/// Errors that can happen while parsing catalog files.
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("invalid XML")]
+    XmlError(#[from] roxmltree::Error),
+
+    #[error("<{element}> element has no {attribute} attribute")]
+    MissingAttribute {
+        element: String,
+        attribute: &'static str,
+    },
+
+    #[error("invalid USB product ID {0:?}")]
+    BadProductId(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOOKUP: &str = r#"<?xml version="1.0"?>
+<INDEX>
+  <PRODUCT USBPID="400D" PATH="https://downloads.bose.com/lando/index.xml"/>
+  <product usbpid="0x4020" path="/ced/qc35ii/index.xml"/>
+  <COMMENT>ignored</COMMENT>
+</INDEX>"#;
+
+    #[test]
+    fn parses_lookup() {
+        let lookup = Lookup::parse(LOOKUP).unwrap();
+        assert_eq!(lookup.products.len(), 2);
+
+        let lando = &lookup.products[0];
+        assert_eq!(lando.dfu_pid, 0x400d);
+        assert_eq!(lando.index_path(), "lando/index.xml");
+        assert_eq!(lando.codename(), Some("lando"));
+        assert_eq!(lando.directory(), "lando");
+
+        let qc35 = &lookup.products[1];
+        assert_eq!(qc35.dfu_pid, 0x4020);
+        assert_eq!(qc35.index_path(), "ced/qc35ii/index.xml");
+        assert_eq!(qc35.codename(), Some("qc35ii"));
+        assert_eq!(qc35.directory(), "ced/qc35ii");
+    }
+
+    #[test]
+    fn finds_products_by_either_usb_id() {
+        let lookup = Lookup::parse(LOOKUP).unwrap();
+        let normal = UsbId {
+            vid: BOSE_VID,
+            pid: 0x40fe,
+        };
+        // Several devices share this normal-mode ID, so it finds the first one listed.
+        assert_eq!(lookup.find(normal).unwrap().dfu_pid, 0x400d);
+
+        let dfu = UsbId {
+            vid: BOSE_VID,
+            pid: 0x4020,
+        };
+        assert_eq!(lookup.find(dfu).unwrap().dfu_pid, 0x4020);
+
+        let other_vendor = UsbId {
+            vid: 0x1234,
+            pid: 0x4020,
+        };
+        assert!(lookup.find(other_vendor).is_none());
+    }
+
+    #[test]
+    fn bad_lookups_are_refused() {
+        assert!(matches!(
+            Lookup::parse("<INDEX><PRODUCT"),
+            Err(Error::XmlError(_))
+        ));
+        assert!(matches!(
+            Lookup::parse(r#"<INDEX><PRODUCT USBPID="400d"/></INDEX>"#),
+            Err(Error::MissingAttribute {
+                attribute: "PATH",
+                ..
+            })
+        ));
+        match Lookup::parse(r#"<INDEX><PRODUCT USBPID="lando" PATH="x"/></INDEX>"#) {
+            Err(Error::BadProductId(pid)) => assert_eq!(pid, "lando"),
+            other => panic!("expected bad product ID, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_index() {
+        let index = Index::parse(
+            r#"<INDEX>
+                 <RELEASE REVISION="1.3.8">
+                   <IMAGE FILENAME="lando_1.3.8.dfu"/>
+                 </RELEASE>
+                 <image filename="extra.dfu"/>
+               </INDEX>"#,
+        )
+        .unwrap();
+        let images: Vec<_> = index
+            .images
+            .iter()
+            .map(|i| (i.filename.as_str(), i.revision.as_deref()))
+            .collect();
+        assert_eq!(
+            images,
+            [("lando_1.3.8.dfu", Some("1.3.8")), ("extra.dfu", None)]
+        );
+        assert_eq!(index.latest().unwrap().filename, "lando_1.3.8.dfu");
+
+        assert!(Index::parse("<INDEX/>").unwrap().latest().is_none());
+        assert!(matches!(
+            Index::parse("<INDEX><IMAGE/></INDEX>"),
+            Err(Error::MissingAttribute {
+                attribute: "FILENAME",
+                ..
+            })
+        ));
+    }
+}
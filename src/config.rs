@@ -0,0 +1,97 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Environment variable that, if set, overrides the location of the configuration file.
+pub const CONFIG_PATH_VAR: &str = "BOSE_DFU_CONFIG";
+
+/// User settings, read from a TOML file. Every field is optional, so an empty or missing file is
+/// equivalent to [Config::default()].
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Friendly names for devices, keyed by USB serial number.
+    pub nicknames: BTreeMap<String, String>,
+
+    /// Base URL of the server to fetch firmware from, if not Bose's official one.
+    pub download_server: Option<String>,
+
+    /// Directory holding the firmware library, if not the default.
+    pub firmware_library: Option<PathBuf>,
+
+    /// Manifest of known-good firmware hashes to check firmware files against before writing them.
+    pub manifest: Option<PathBuf>,
+
+    /// Directory to save firmware backups in, if not the default.
+    pub backup_dir: Option<PathBuf>,
+
+    /// File to keep the update history in, if not the default.
+    pub history: Option<PathBuf>,
+
+    /// File to keep the audit log in. Operations are only audited if this is set.
+    pub audit_log: Option<PathBuf>,
+}
+
+impl Config {
+    /// Where the configuration file lives: the path in [CONFIG_PATH_VAR] if that's set, otherwise
+    /// `bose-dfu/config.toml` inside the OS's configuration directory.
+    pub fn default_path() -> Option<PathBuf> {
+        match std::env::var_os(CONFIG_PATH_VAR) {
+            Some(path) => Some(path.into()),
+            None => dirs::config_dir().map(|d| d.join("bose-dfu").join("config.toml")),
+        }
+    }
+
+    /// Load the configuration file at [Config::default_path()], or return an empty configuration
+    /// if there isn't one.
+    pub fn load_default() -> Result<Self, Error> {
+        match Self::default_path() {
+            Some(path) if path.exists() => Self::load(&path),
+            _ => Ok(Self::default()),
+        }
+    }
+
+    /// Load the configuration file at `path`.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let text = std::fs::read_to_string(path).map_err(|source| Error::IoError {
+            source,
+            path: path.to_owned(),
+        })?;
+
+        toml::from_str(&text).map_err(|source| Error::ParseError {
+            source,
+            path: path.to_owned(),
+        })
+    }
+
+    /// Find the nickname given to the device with the given USB serial number, if any.
+    pub fn nickname_for(&self, serial: &str) -> Option<&str> {
+        self.nicknames.get(serial).map(String::as_str)
+    }
+
+    /// Find the USB serial number of the device with the given nickname, if any.
+    pub fn serial_for(&self, nickname: &str) -> Option<&str> {
+        self.nicknames
+            .iter()
+            .find(|(_, name)| *name == nickname)
+            .map(|(serial, _)| serial.as_str())
+    }
+}
+
+/// Errors that can happen while loading the configuration file.
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("failed to read configuration file {}", .path.display())]
+    IoError {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+
+    #[error("invalid configuration file {}", .path.display())]
+    ParseError {
+        source: toml::de::Error,
+        path: PathBuf,
+    },
+}
@@ -1,29 +1,37 @@
+use serde::{Deserialize, Serialize};
 use std::fmt::Display;
+use std::str::FromStr;
+use thiserror::Error;
 
-const BOSE_VID: u16 = 0x05a7;
+pub const BOSE_VID: u16 = 0x05a7;
 const BOSE_HID_USAGE_PAGE: u16 = 0xff00;
 
 // TODO: It turns out that many devices share the same normal mode PID, so
 // we should at least tweak the wording that currently lists everything with
 // a PID in this list as a "compatible device". Perhaps add an additional
 // match on product string?
-const COMPATIBLE_DEVICES: &[DeviceIds] = &[
-    // Bose Color II SoundLink
-    bose_dev(0x40fe, 0x400d),
-    // Bose SoundLink Mini II
-    bose_dev(0x40fe, 0x4009),
-    // Bose QC35 II
-    bose_dev(0x40fe, 0x4020),
+//
+// None of these devices reads back exactly what was written to it, and nobody has yet worked out
+// how what they read back relates to it, so none has a readback rule.
+pub const COMPATIBLE_DEVICES: &[DeviceIds] = &[
+    bose_dev("Bose Color II SoundLink", 0x40fe, 0x400d, None),
+    bose_dev("Bose SoundLink Mini II", 0x40fe, 0x4009, None),
+    bose_dev("Bose QC35 II", 0x40fe, 0x4020, None),
 ];
 
 // Use UsbId instead of DeviceIds since some incompatible devices don't have a concept of DFU mode.
-const INCOMPATIBLE_DEVICES: &[UsbId] = &[
-    // Bose Noise Cancelling Headphones 700
-    bose_pid(0x40fc),
-];
-
-const fn bose_dev(normal_pid: u16, dfu_pid: u16) -> DeviceIds {
+pub const INCOMPATIBLE_DEVICES: &[(&str, UsbId)] =
+    &[("Bose Noise Cancelling Headphones 700", bose_pid(0x40fc))];
+
+const fn bose_dev(
+    name: &'static str,
+    normal_pid: u16,
+    dfu_pid: u16,
+    readback: Option<ReadbackRule>,
+) -> DeviceIds {
     DeviceIds {
+        name,
+        readback,
         normal_mode: UsbId {
             vid: BOSE_VID,
             pid: normal_pid,
@@ -55,7 +63,7 @@ pub fn identify_device(id: UsbId, usage_page: u16) -> DeviceCompat {
     }
 
     // Next, see if it's known to be incompatible.
-    if INCOMPATIBLE_DEVICES.contains(&id) {
+    if INCOMPATIBLE_DEVICES.iter().any(|&(_, known)| known == id) {
         return DeviceCompat::Incompatible;
     }
 
@@ -67,6 +75,37 @@ pub fn identify_device(id: UsbId, usage_page: u16) -> DeviceCompat {
     }
 }
 
+/// Find the USB IDs a compatible device uses in each of its modes, given its ID in either mode.
+pub fn find_device_ids(id: UsbId) -> Option<DeviceIds> {
+    COMPATIBLE_DEVICES
+        .iter()
+        .find(|candidate| candidate.match_id(id).is_some())
+        .copied()
+}
+
+/// Find the names of all known devices, compatible or not, that use the given USB ID in any mode.
+/// There can be more than one since many devices share the same normal mode ID.
+pub fn device_names(id: UsbId) -> Vec<&'static str> {
+    let compatible = COMPATIBLE_DEVICES
+        .iter()
+        .filter(|candidate| candidate.match_id(id).is_some())
+        .map(|candidate| candidate.name);
+    let incompatible = INCOMPATIBLE_DEVICES
+        .iter()
+        .filter(|&&(_, known)| known == id)
+        .map(|&(name, _)| name);
+    compatible.chain(incompatible).collect()
+}
+
+/// Find a compatible device by its name (ignoring case) or by its DFU-mode USB ID in "vvvv:pppp"
+/// format. Normal-mode IDs aren't accepted, since many devices share them.
+pub fn find_device(name_or_id: &str) -> Option<&'static DeviceIds> {
+    let id = name_or_id.parse::<UsbId>().ok();
+    COMPATIBLE_DEVICES.iter().find(|candidate| {
+        candidate.name.eq_ignore_ascii_case(name_or_id) || Some(candidate.dfu_mode) == id
+    })
+}
+
 /// Compatibility of a device, with detected mode if applicable.
 pub enum DeviceCompat {
     /// Known to speak the Bose DFU protocol. Usable by default.
@@ -89,15 +128,38 @@ impl Display for DeviceCompat {
     }
 }
 
+/// The USB IDs a compatible device presents in each of its modes.
+#[derive(Copy, Clone, Debug)]
+pub struct DeviceIds {
+    pub name: &'static str,
+    pub normal_mode: UsbId,
+    pub dfu_mode: UsbId,
+    /// How to find the firmware that was written to the device in what it reads back, if known.
+    pub readback: Option<ReadbackRule>,
+}
+
+/// How the image a device reads back in DFU mode relates to the firmware payload last written to
+/// it, which is what makes it possible to verify a download. Add variants here as other layouts
+/// are worked out.
 #[derive(Copy, Clone, Debug)]
-struct DeviceIds {
-    normal_mode: UsbId,
-    dfu_mode: UsbId,
+pub enum ReadbackRule {
+    /// The device reads back exactly the payload that was written.
+    Exact,
+}
+
+impl ReadbackRule {
+    /// Find the part of `read_back` that should match a payload of `payload_len` bytes, or [None]
+    /// if `read_back` is too short to contain it.
+    pub fn normalize(self, read_back: &[u8], payload_len: usize) -> Option<&[u8]> {
+        match self {
+            ReadbackRule::Exact => (read_back.len() >= payload_len).then_some(read_back),
+        }
+    }
 }
 
 impl DeviceIds {
     /// If one of our modes uses with the given ID, return it. Otherwise, return [None].
-    fn match_id(&self, id: UsbId) -> Option<DeviceMode> {
+    pub fn match_id(&self, id: UsbId) -> Option<DeviceMode> {
         if id == self.normal_mode {
             Some(DeviceMode::Normal)
         } else if id == self.dfu_mode {
@@ -127,7 +189,8 @@ impl Display for DeviceMode {
 }
 
 /// A USB vendor ID and product ID pair.
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
 pub struct UsbId {
     pub vid: u16,
     pub pid: u16,
@@ -138,3 +201,38 @@ impl Display for UsbId {
         write!(f, "{:04x}:{:04x}", self.vid, self.pid)
     }
 }
+
+/// Parse from the same "vvvv:pppp" hex format that [Display] produces.
+impl FromStr for UsbId {
+    type Err = ParseUsbIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (vid, pid) = s
+            .split_once(':')
+            .ok_or_else(|| ParseUsbIdError(s.to_owned()))?;
+        let parse = |id| u16::from_str_radix(id, 16).map_err(|_| ParseUsbIdError(s.to_owned()));
+        Ok(UsbId {
+            vid: parse(vid)?,
+            pid: parse(pid)?,
+        })
+    }
+}
+
+impl TryFrom<String> for UsbId {
+    type Error = ParseUsbIdError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<UsbId> for String {
+    fn from(id: UsbId) -> Self {
+        id.to_string()
+    }
+}
+
+/// Error returned when a string isn't a valid [UsbId].
+#[derive(Error, Debug)]
+#[error("invalid USB ID {0:?}: expected two hex numbers separated by a colon, like 05a7:40fe")]
+pub struct ParseUsbIdError(String);
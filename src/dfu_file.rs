@@ -1,13 +1,45 @@
+use crate::device_ids::UsbId;
+use crate::dfuse::DFUSE_DFU_BCD;
+use crate::version::FirmwareVersion;
 use byteorder::{BE, ByteOrder};
-use log::warn;
+use serde::{Deserialize, Serialize};
 use std::fmt::{Display, LowerHex, Write};
-use std::io::{Read, Seek, SeekFrom};
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
 use thiserror::Error;
 
+/// Length of a DFU suffix without any extension bytes.
+const MIN_SUFFIX_LEN: u8 = 0x10;
+
+/// The bcdDFU value DFU 1.0 and 1.1 files are supposed to have.
+const STANDARD_DFU_BCD: u16 = 0x0100;
+
+/// Payloads shorter than this are suspiciously small for real firmware.
+const TINY_PAYLOAD_LEN: u64 = 1024;
+
+/// How [parse_with] treats problems that don't stop a file from being usable.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Validation {
+    /// Record problems in [SuffixInfo::warnings] and leave it to the caller to decide what to do.
+    #[default]
+    Lenient,
+    /// Fail with [SuffixError::Strict] on the first problem.
+    Strict,
+}
+
 /// Parse the suffix of a DFU file and calculate the data's real checksum, storing the results in a
 /// [SuffixInfo] struct. When this returns, `file`'s cursor is at the beginning of the payload.
+/// Equivalent to [parse_with] with [Validation::Lenient].
 pub fn parse(file: &mut (impl Read + Seek)) -> Result<SuffixInfo, Error> {
-    const MIN_SUFFIX_LEN: u8 = 0x10;
+    parse_with(file, Validation::Lenient)
+}
+
+/// Like [parse], but with a choice of how to treat non-fatal problems.
+pub fn parse_with(
+    file: &mut (impl Read + Seek),
+    validation: Validation,
+) -> Result<SuffixInfo, Error> {
     const MIN_DFU_BCD: u16 = 0x0100;
 
     let file_len = file.seek(SeekFrom::End(0))?;
@@ -28,18 +60,12 @@ pub fn parse(file: &mut (impl Read + Seek)) -> Result<SuffixInfo, Error> {
     }
 
     let suffix_len = suffix[4];
-    #[allow(clippy::comparison_chain)]
     if suffix_len < MIN_SUFFIX_LEN {
         return Err(SuffixError::SuffixTooShort {
             minimum: MIN_SUFFIX_LEN as _,
             actual: suffix_len,
         }
         .into());
-    } else if suffix_len > MIN_SUFFIX_LEN {
-        warn!(
-            "Got {} extra bytes in DFU suffix; continuing",
-            suffix_len - MIN_SUFFIX_LEN
-        );
     }
 
     let payload_length = match file_len.checked_sub(suffix_len as _) {
@@ -62,6 +88,11 @@ pub fn parse(file: &mut (impl Read + Seek)) -> Result<SuffixInfo, Error> {
         .into());
     }
 
+    // Extension bytes, if any, sit between the payload and the standard fields.
+    let mut extension = vec![0u8; (suffix_len - MIN_SUFFIX_LEN) as usize];
+    file.seek(SeekFrom::Start(payload_length))?;
+    file.read_exact(&mut extension)?;
+
     // CRC is over all but the last 4 bytes, which hold the expected CRC.
     file.rewind()?;
     let actual_crc = compute_crc(&mut file.take(file_len - 4))?;
@@ -70,14 +101,82 @@ pub fn parse(file: &mut (impl Read + Seek)) -> Result<SuffixInfo, Error> {
     // Reset cursor so caller can read the file's data.
     file.rewind()?;
 
-    Ok(SuffixInfo {
+    let mut info = SuffixInfo {
         vendor_id: BE::read_u16(&suffix[10..12]).into(),
         product_id: BE::read_u16(&suffix[12..14]).into(),
         release_number: BE::read_u16(&suffix[14..16]).into(),
+        dfu_version: bcd_dfu,
+        suffix_length: suffix_len,
+        extension,
         expected_crc,
         actual_crc,
         payload_length,
-    })
+        warnings: vec![],
+    };
+
+    info.warnings = find_warnings(&info);
+    if validation == Validation::Strict
+        && let Some(warning) = info.warnings.first()
+    {
+        return Err(SuffixError::Strict(warning.clone()).into());
+    }
+
+    Ok(info)
+}
+
+fn find_warnings(info: &SuffixInfo) -> Vec<SuffixWarning> {
+    let mut warnings = vec![];
+
+    if info.vendor_id.0.is_none() || info.product_id.0.is_none() {
+        warnings.push(SuffixWarning::WildcardId);
+    }
+    if !info.extension.is_empty() {
+        warnings.push(SuffixWarning::LongSuffix(info.extension.len()));
+    }
+    if ![STANDARD_DFU_BCD, DFUSE_DFU_BCD].contains(&info.dfu_version) {
+        warnings.push(SuffixWarning::UnexpectedDfuVersion(info.dfu_version));
+    }
+    if info.release_number.0.is_none() {
+        warnings.push(SuffixWarning::NoReleaseNumber);
+    }
+    if info.payload_length < TINY_PAYLOAD_LEN {
+        warnings.push(SuffixWarning::TinyPayload(info.payload_length));
+    }
+
+    warnings
+}
+
+/// Write a complete DFU file to `out`: `payload`, followed by a suffix holding the IDs, release
+/// number, DFU version, and extension bytes from `suffix`. The suffix length and CRC are computed
+/// rather than copied from `suffix`, so writing out the payload and suffix of a parsed file with a
+/// valid CRC reproduces that file exactly. Returns the CRC written.
+pub fn write(
+    out: &mut impl std::io::Write,
+    payload: &[u8],
+    suffix: &SuffixInfo,
+) -> Result<u32, Error> {
+    let suffix_len = u8::try_from(suffix.extension.len() + MIN_SUFFIX_LEN as usize)
+        .map_err(|_| SuffixError::ExtensionTooLong(suffix.extension.len()))?;
+
+    // All but the CRC, in file order. Unlike the extension, the standard fields are little-endian.
+    let mut tail = suffix.extension.clone();
+    for field in [
+        suffix.release_number.into(),
+        suffix.product_id.into(),
+        suffix.vendor_id.into(),
+        suffix.dfu_version,
+    ] {
+        tail.extend(u16::to_le_bytes(field));
+    }
+    tail.extend(b"UFD");
+    tail.push(suffix_len);
+
+    let crc = compute_crc(&mut payload.chain(&tail[..]))?;
+    out.write_all(payload)?;
+    out.write_all(&tail)?;
+    out.write_all(&crc.to_le_bytes())?;
+
+    Ok(crc)
 }
 
 /// Compute the CRC used by USB DFU 1.1 over all bytes in the given file. Does not strip CRC field
@@ -97,22 +196,167 @@ fn compute_crc(file: &mut impl Read) -> std::io::Result<u32> {
     Ok(!hasher.finalize()) // DFU's CRC algorithm is a bitwise NOT of IEEE's.
 }
 
-/// Metadata about a file containing a DFU suffix.
+/// A DFU file whose suffix has been parsed, along with the reader or buffer it came from. Takes
+/// care of finding the payload, so callers don't have to remember where [parse] leaves the cursor
+/// or how long the payload is.
+///
+/// Construction fails if the suffix can't be parsed, but not if the CRC is wrong, so that callers
+/// can still inspect corrupt files; use [DfuFile::ensure_valid_crc] before writing one to a device.
 #[derive(Debug)]
+pub struct DfuFile<R> {
+    reader: R,
+    suffix: SuffixInfo,
+}
+
+impl<R: Read + Seek> DfuFile<R> {
+    /// Parse the DFU file in `reader`, with [Validation::Lenient].
+    pub fn new(reader: R) -> Result<Self, Error> {
+        Self::with_validation(reader, Validation::Lenient)
+    }
+
+    /// Parse the DFU file in `reader`, with a choice of how to treat non-fatal problems.
+    pub fn with_validation(mut reader: R, validation: Validation) -> Result<Self, Error> {
+        let suffix = parse_with(&mut reader, validation)?;
+        Ok(Self { reader, suffix })
+    }
+
+    pub fn suffix(&self) -> &SuffixInfo {
+        &self.suffix
+    }
+
+    /// Get a reader over the file's payload, without the suffix.
+    pub fn payload_reader(&mut self) -> Result<impl Read + '_, Error> {
+        self.reader.rewind()?;
+        Ok(self.reader.by_ref().take(self.suffix.payload_length))
+    }
+
+    /// Read the file's whole payload, without the suffix, into memory.
+    pub fn payload_bytes(&mut self) -> Result<Vec<u8>, Error> {
+        let mut payload = Vec::with_capacity(self.suffix.payload_length as usize);
+        self.payload_reader()?.read_to_end(&mut payload)?;
+        Ok(payload)
+    }
+
+    /// Check if the file's USB ID matches a device with the given ID. IDs in the file that are
+    /// wildcards match any device.
+    pub fn matches_device(&self, id: UsbId) -> bool {
+        self.suffix.vendor_id.matches(id.vid) && self.suffix.product_id.matches(id.pid)
+    }
+
+    pub fn has_valid_crc(&self) -> bool {
+        self.suffix.has_valid_crc()
+    }
+
+    pub fn ensure_valid_crc(&self) -> Result<(), SuffixError> {
+        self.suffix.ensure_valid_crc()
+    }
+
+    /// Borrow the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    /// Give back the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl DfuFile<File> {
+    /// Open and parse the DFU file at `path`.
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        Self::new(File::open(path)?)
+    }
+}
+
+impl<'a> DfuFile<Cursor<&'a [u8]>> {
+    /// Parse a DFU file that's already in memory.
+    pub fn from_bytes(data: &'a [u8]) -> Result<Self, Error> {
+        Self::new(Cursor::new(data))
+    }
+
+    /// Borrow the payload directly from the buffer, without copying it.
+    pub fn payload(&self) -> &'a [u8] {
+        &self.reader.get_ref()[..self.suffix.payload_length as usize]
+    }
+}
+
+/// Metadata about a file containing a DFU suffix.
+#[derive(Debug, Clone)]
 pub struct SuffixInfo {
     pub vendor_id: OptionalId,
     pub product_id: OptionalId,
     pub release_number: OptionalId,
+    /// Version of the DFU spec the suffix follows (bcdDFU), in binary-coded decimal.
+    pub dfu_version: u16,
+    /// Length of the suffix (bLength), including any bytes beyond the standard 16.
+    pub suffix_length: u8,
+    /// Bytes beyond the standard 16 in the suffix, in file order. See
+    /// [SuffixInfo::decode_extension].
+    pub extension: Vec<u8>,
     pub expected_crc: u32,
     pub actual_crc: u32,
     pub payload_length: u64,
+    /// Problems found while parsing that don't stop the file from being usable.
+    pub warnings: Vec<SuffixWarning>,
 }
 
 impl SuffixInfo {
+    /// Describe a standard 16-byte DFU 1.0 suffix with the given fields, for passing to [write]
+    /// along with a payload. The CRC fields and payload length are left zero, since [write]
+    /// computes them from the payload.
+    pub fn new(vendor_id: OptionalId, product_id: OptionalId, release_number: OptionalId) -> Self {
+        Self {
+            vendor_id,
+            product_id,
+            release_number,
+            dfu_version: STANDARD_DFU_BCD,
+            suffix_length: MIN_SUFFIX_LEN,
+            extension: vec![],
+            expected_crc: 0,
+            actual_crc: 0,
+            payload_length: 0,
+            warnings: vec![],
+        }
+    }
+
     pub fn has_valid_crc(&self) -> bool {
         self.actual_crc == self.expected_crc
     }
 
+    /// The firmware version this file claims to contain, decoded from its release number.
+    pub fn firmware_version(&self) -> Option<FirmwareVersion> {
+        self.release_number.0.and_then(FirmwareVersion::from_bcd)
+    }
+
+    /// Check if this is the suffix of a DfuSe file, whose payload must be parsed with
+    /// [crate::dfuse::parse].
+    pub fn is_dfuse(&self) -> bool {
+        self.dfu_version == DFUSE_DFU_BCD
+    }
+
+    /// Interpret the suffix's extension bytes, if it has any. The DFU spec leaves their format up to
+    /// vendors and Bose doesn't document one, so the only format recognized is plain text.
+    pub fn decode_extension(&self) -> Option<SuffixExtension<'_>> {
+        if self.extension.is_empty() {
+            return None;
+        }
+
+        // Allow NUL padding after the text.
+        let end = self
+            .extension
+            .iter()
+            .rposition(|&b| b != 0)
+            .map_or(0, |i| i + 1);
+        let text = &self.extension[..end];
+        if !text.is_empty() && text.iter().all(|&b| b.is_ascii_graphic() || b == b' ') {
+            // Printable ASCII is always valid UTF-8.
+            return Some(SuffixExtension::Text(std::str::from_utf8(text).unwrap()));
+        }
+
+        Some(SuffixExtension::Unknown(&self.extension))
+    }
+
     pub fn ensure_valid_crc(&self) -> Result<(), SuffixError> {
         match self.has_valid_crc() {
             true => Ok(()),
@@ -124,8 +368,35 @@ impl SuffixInfo {
     }
 }
 
-/// A 16-bit ID that may be unset. Has functions for pretty-printing and wildcard matching.
+/// A decoded DFU suffix extension.
 #[derive(Debug)]
+pub enum SuffixExtension<'a> {
+    /// Printable ASCII text, possibly followed by NUL padding.
+    Text(&'a str),
+    /// Bytes in a format we don't recognize.
+    Unknown(&'a [u8]),
+}
+
+/// Find runs of at least `min_len` printable ASCII characters in `data`, like the `strings`
+/// utility. Returns each run along with its offset in `data`.
+pub fn printable_strings(data: &[u8], min_len: usize) -> Vec<(usize, &str)> {
+    let is_printable = |b: &u8| b.is_ascii_graphic() || *b == b' ';
+
+    let mut found = vec![];
+    let mut start = 0;
+    for run in data.split(|b| !is_printable(b)) {
+        if run.len() >= min_len {
+            // Runs only contain ASCII, so they're always valid UTF-8.
+            found.push((start, std::str::from_utf8(run).unwrap()));
+        }
+        start += run.len() + 1;
+    }
+    found
+}
+
+/// A 16-bit ID that may be unset. Has functions for pretty-printing and wildcard matching.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct OptionalId(pub Option<u16>);
 
 impl OptionalId {
@@ -174,6 +445,13 @@ impl From<u16> for OptionalId {
     }
 }
 
+/// Convert to an ID field in a DFU suffix.
+impl From<OptionalId> for u16 {
+    fn from(val: OptionalId) -> Self {
+        val.0.unwrap_or(0xffff)
+    }
+}
+
 /// All errors (parse and I/O) that can happen while reading a DFU file.
 #[derive(Error, Debug)]
 #[non_exhaustive]
@@ -185,6 +463,29 @@ pub enum Error {
     IoError(#[from] std::io::Error),
 }
 
+/// Non-fatal problems with a DFU file, collected in [SuffixInfo::warnings].
+#[derive(Error, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum SuffixWarning {
+    #[error("USB vendor or product ID is a wildcard, so the file can't be matched to a device")]
+    WildcardId,
+
+    #[error("DFU suffix has {0} extra bytes")]
+    LongSuffix(usize),
+
+    #[error(
+        "DFU specification version is {}.{}, not the expected 1.0 (or 1.1a for DfuSe)",
+        .0 >> 8, .0 & 0xff,
+    )]
+    UnexpectedDfuVersion(u16),
+
+    #[error("release number is unset")]
+    NoReleaseNumber,
+
+    #[error("payload is only {0} bytes, too small to be real firmware")]
+    TinyPayload(u64),
+}
+
 /// Parse errors for a DFU suffix.
 #[derive(Error, Debug)]
 #[non_exhaustive]
@@ -208,6 +509,41 @@ pub enum SuffixError {
     #[error("DFU suffix is longer than file: suffix is {suffix_len} bytes, file is {file_len}")]
     SuffixTooLong { suffix_len: u8, file_len: u64 },
 
+    #[error("DFU suffix extension is too long: {0} bytes")]
+    ExtensionTooLong(usize),
+
+    #[error("file failed strict validation")]
+    Strict(#[source] SuffixWarning),
+
     #[error("bad CRC32 checksum: expected {expected:#010x}, got {actual:#010x}")]
     BadCRC { expected: u32, actual: u32 },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_reproduces_parsed_files() {
+        for name in ["correct.dfu", "long_suffix.dfu", "wildcard_vid.dfu"] {
+            let data = std::fs::read(Path::new("test_data/dfu").join(name)).unwrap();
+            let file = DfuFile::from_bytes(&data).unwrap();
+            assert!(file.has_valid_crc(), "{name}");
+
+            let mut written = vec![];
+            let crc = write(&mut written, file.payload(), file.suffix()).unwrap();
+            assert_eq!(crc, file.suffix().expected_crc, "{name}");
+            assert_eq!(written, data, "{name}");
+        }
+    }
+
+    #[test]
+    fn write_refuses_oversized_extension() {
+        let mut suffix = SuffixInfo::new(OptionalId(None), OptionalId(None), OptionalId(None));
+        suffix.extension = vec![0; 256];
+        assert!(matches!(
+            write(&mut vec![], b"payload", &suffix),
+            Err(Error::SuffixError(SuffixError::ExtensionTooLong(256)))
+        ));
+    }
+}
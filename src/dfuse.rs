@@ -0,0 +1,252 @@
+use byteorder::{ByteOrder, LE};
+use thiserror::Error;
+
+/// The bcdDFU value in the suffix of a DfuSe file.
+pub const DFUSE_DFU_BCD: u16 = 0x011a;
+
+const PREFIX_LEN: usize = 11;
+const TARGET_PREFIX_LEN: usize = 274;
+const ELEMENT_HEADER_LEN: usize = 8;
+const TARGET_NAME_LEN: usize = 255;
+
+/// The contents of a DfuSe file, ST's extension of the DFU file format for STM32 bootloaders
+/// (documented in ST's UM0391). A DfuSe file's DFU payload holds one or more targets (one per
+/// alternate setting of the DFU interface), each holding elements that are written to given
+/// memory addresses.
+///
+/// Bose devices don't use this format, but DfuSe files turn up in mixed firmware collections.
+#[derive(Debug)]
+pub struct DfuseImage {
+    /// Version of the DfuSe format (bVersion).
+    pub version: u8,
+    /// Size of the file (DFUImageSize), as recorded in the prefix. Tools disagree on whether this
+    /// includes the DFU suffix, so it isn't checked.
+    pub image_size: u32,
+    pub targets: Vec<DfuseTarget>,
+}
+
+/// One target in a DfuSe file.
+#[derive(Debug)]
+pub struct DfuseTarget {
+    pub alternate_setting: u8,
+    /// The target's name, if it has one.
+    pub name: Option<String>,
+    pub elements: Vec<DfuseElement>,
+}
+
+/// One element of a DfuSe target: a block of data to be written at a given address.
+#[derive(Debug)]
+pub struct DfuseElement {
+    pub address: u32,
+    pub size: u32,
+    /// Where the element's data starts, relative to the start of the file.
+    pub offset: usize,
+}
+
+/// Check if a DFU payload starts with the DfuSe prefix signature.
+pub fn has_prefix(payload: &[u8]) -> bool {
+    payload.starts_with(b"DfuSe")
+}
+
+/// Parse the DfuSe prefix, targets, and elements in a DFU file's payload (that is, the file without
+/// its DFU suffix).
+pub fn parse(payload: &[u8]) -> Result<DfuseImage, Error> {
+    if !has_prefix(payload) {
+        return Err(Error::BadSignature("DfuSe"));
+    }
+    let prefix = take(payload, 0, PREFIX_LEN, "prefix")?;
+
+    let version = prefix[5];
+    let image_size = LE::read_u32(&prefix[6..10]);
+
+    let mut offset = PREFIX_LEN;
+    let mut targets = vec![];
+    for _ in 0..prefix[10] {
+        let header = take(payload, offset, TARGET_PREFIX_LEN, "target prefix")?;
+        if &header[0..6] != b"Target" {
+            return Err(Error::BadSignature("Target"));
+        }
+        offset += TARGET_PREFIX_LEN;
+
+        let named = LE::read_u32(&header[7..11]) != 0;
+        let name_bytes = &header[11..11 + TARGET_NAME_LEN];
+        let name_len = name_bytes
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(TARGET_NAME_LEN);
+        let name = named.then(|| String::from_utf8_lossy(&name_bytes[..name_len]).into_owned());
+
+        let target_size = LE::read_u32(&header[266..270]) as usize;
+        let element_count = LE::read_u32(&header[270..274]);
+        let target_start = offset;
+
+        let mut elements = vec![];
+        for _ in 0..element_count {
+            let element = take(payload, offset, ELEMENT_HEADER_LEN, "element header")?;
+            let address = LE::read_u32(&element[0..4]);
+            let size = LE::read_u32(&element[4..8]);
+            offset += ELEMENT_HEADER_LEN;
+
+            take(payload, offset, size as usize, "element data")?;
+            elements.push(DfuseElement {
+                address,
+                size,
+                offset,
+            });
+            offset += size as usize;
+        }
+
+        if offset - target_start != target_size {
+            return Err(Error::SizeMismatch {
+                expected: target_size,
+                actual: offset - target_start,
+            });
+        }
+
+        targets.push(DfuseTarget {
+            alternate_setting: header[6],
+            name,
+            elements,
+        });
+    }
+
+    Ok(DfuseImage {
+        version,
+        image_size,
+        targets,
+    })
+}
+
+/// Get `len` bytes of `data` starting at `offset`, or fail saying that `what` is truncated.
+fn take<'a>(
+    data: &'a [u8],
+    offset: usize,
+    len: usize,
+    what: &'static str,
+) -> Result<&'a [u8], Error> {
+    offset
+        .checked_add(len)
+        .and_then(|end| data.get(offset..end))
+        .ok_or(Error::Truncated(what))
+}
+
+/// Errors that can happen while parsing a DfuSe file.
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("DfuSe {0:?} signature is not present")]
+    BadSignature(&'static str),
+
+    #[error("DfuSe file is truncated: {0} is cut off")]
+    Truncated(&'static str),
+
+    #[error("DfuSe target size doesn't match contents: says {expected} bytes, found {actual}")]
+    SizeMismatch { expected: usize, actual: usize },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a DfuSe payload with one named target at alternate setting 2, holding an element for
+    /// each of `elements`.
+    fn image(elements: &[(u32, &[u8])]) -> Vec<u8> {
+        let mut target = vec![];
+        for (address, data) in elements {
+            target.extend(address.to_le_bytes());
+            target.extend((data.len() as u32).to_le_bytes());
+            target.extend(*data);
+        }
+
+        let mut out = b"DfuSe\x01".to_vec();
+        out.extend(0u32.to_le_bytes()); // DFUImageSize, which isn't checked
+        out.push(1);
+        out.extend(b"Target\x02");
+        out.extend(1u32.to_le_bytes());
+        let mut name = b"Internal Flash".to_vec();
+        name.resize(TARGET_NAME_LEN, 0);
+        out.extend(name);
+        out.extend((target.len() as u32).to_le_bytes());
+        out.extend((elements.len() as u32).to_le_bytes());
+        out.extend(target);
+        out
+    }
+
+    #[test]
+    fn parses_targets_and_elements() {
+        let data = image(&[(0x0800_0000, b"abcd"), (0x0800_4000, b"ef")]);
+        let parsed = parse(&data).unwrap();
+        assert_eq!(parsed.version, 1);
+        assert_eq!(parsed.targets.len(), 1);
+
+        let target = &parsed.targets[0];
+        assert_eq!(target.alternate_setting, 2);
+        assert_eq!(target.name.as_deref(), Some("Internal Flash"));
+        let elements: Vec<_> = target
+            .elements
+            .iter()
+            .map(|e| {
+                (
+                    e.address,
+                    e.size,
+                    &data[e.offset..e.offset + e.size as usize],
+                )
+            })
+            .collect();
+        assert_eq!(
+            elements,
+            [(0x0800_0000, 4, &b"abcd"[..]), (0x0800_4000, 2, &b"ef"[..])]
+        );
+    }
+
+    #[test]
+    fn truncated_images_are_refused() {
+        let data = image(&[(0x0800_0000, b"abcd")]);
+        let cases = [
+            (PREFIX_LEN - 1, "prefix"),
+            (PREFIX_LEN + TARGET_PREFIX_LEN - 1, "target prefix"),
+            (PREFIX_LEN + TARGET_PREFIX_LEN + 4, "element header"),
+            (data.len() - 1, "element data"),
+        ];
+        for (len, what) in cases {
+            match parse(&data[..len]) {
+                Err(Error::Truncated(cut)) => assert_eq!(cut, what),
+                other => panic!("{len} bytes: expected {what} truncated, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn oversized_element_is_truncated_not_overflowed() {
+        let mut data = image(&[(0, b"abcd")]);
+        let size_at = PREFIX_LEN + TARGET_PREFIX_LEN + 4;
+        data[size_at..size_at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            parse(&data),
+            Err(Error::Truncated("element data"))
+        ));
+    }
+
+    #[test]
+    fn bad_signatures_and_sizes_are_refused() {
+        assert!(matches!(
+            parse(b"not a DfuSe file"),
+            Err(Error::BadSignature("DfuSe"))
+        ));
+
+        let mut data = image(&[(0, b"abcd")]);
+        data[PREFIX_LEN] = b't';
+        assert!(matches!(parse(&data), Err(Error::BadSignature("Target"))));
+
+        let mut data = image(&[(0, b"abcd")]);
+        let size_at = PREFIX_LEN + 266;
+        data[size_at..size_at + 4].copy_from_slice(&100u32.to_le_bytes());
+        assert!(matches!(
+            parse(&data),
+            Err(Error::SizeMismatch {
+                expected: 100,
+                actual: 12
+            })
+        ));
+    }
+}
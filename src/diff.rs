@@ -0,0 +1,369 @@
+use crate::dfu_file::printable_strings;
+use std::collections::HashSet;
+use std::ops::Range;
+
+/// The result of comparing two byte strings in fixed-size blocks, such as two firmware payloads in
+/// the blocks [crate::protocol::XFER_DATA_SIZE] they're transferred in.
+#[derive(Debug)]
+pub struct BlockDiff {
+    pub block_size: usize,
+    /// Number of blocks in the longer of the two byte strings.
+    pub total_blocks: usize,
+    /// Runs of consecutive blocks that differ, as ranges of block indices. Blocks past the end of
+    /// the shorter byte string count as different.
+    pub changed: Vec<Range<usize>>,
+}
+
+impl BlockDiff {
+    /// Compare `old` and `new` in blocks of `block_size` bytes, starting at the beginning of each.
+    pub fn compare(old: &[u8], new: &[u8], block_size: usize) -> Self {
+        assert!(block_size > 0, "block size must be nonzero");
+
+        let total_blocks = old.len().max(new.len()).div_ceil(block_size);
+
+        let mut changed: Vec<Range<usize>> = vec![];
+        for i in 0..total_blocks {
+            if block(old, i, block_size) == block(new, i, block_size) {
+                continue;
+            }
+            match changed.last_mut() {
+                Some(run) if run.end == i => run.end += 1,
+                _ => changed.push(i..i + 1),
+            }
+        }
+
+        Self {
+            block_size,
+            total_blocks,
+            changed,
+        }
+    }
+
+    /// Total number of blocks that differ.
+    pub fn changed_blocks(&self) -> usize {
+        self.changed.iter().map(|run| run.len()).sum()
+    }
+
+    /// Percentage of blocks that differ. Two empty byte strings are 0% different.
+    pub fn percent_changed(&self) -> f64 {
+        match self.total_blocks {
+            0 => 0.0,
+            total => self.changed_blocks() as f64 * 100.0 / total as f64,
+        }
+    }
+
+    /// The byte offsets covered by a run of blocks from [BlockDiff::changed].
+    pub fn byte_range(&self, run: &Range<usize>) -> Range<usize> {
+        run.start * self.block_size..run.end * self.block_size
+    }
+}
+
+/// How an image read back from a device differs from the image that was written to it, as a
+/// starting point for working out what the device does to firmware between download and upload.
+#[derive(Debug)]
+pub struct ReadbackAnalysis {
+    /// Which blocks differ at the same offset.
+    pub blocks: BlockDiff,
+    pub tail: Tail,
+    /// Changed blocks that read back as a single repeated byte (for example, erased flash), along
+    /// with that byte.
+    pub fill_blocks: Vec<(usize, u8)>,
+    /// Where the other changed blocks of the written image turn up in the read-back image.
+    pub relocations: Vec<Relocation>,
+}
+
+/// How the end of a read-back image compares to the written image.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Tail {
+    SameLength,
+    /// The read-back image has `len` extra bytes at the end, which are all `fill` if they're a
+    /// single repeated byte.
+    Extra {
+        len: usize,
+        fill: Option<u8>,
+    },
+    /// The read-back image is this many bytes shorter.
+    Missing(usize),
+}
+
+/// Where a changed block of a written image turns up in the read-back image.
+#[derive(Debug)]
+pub struct Relocation {
+    pub block: usize,
+    /// Offset of the block's contents in the read-back image, if they're anywhere in it. If they're
+    /// in more than one place, the one closest to the block's original offset.
+    pub found_at: Option<usize>,
+}
+
+impl ReadbackAnalysis {
+    /// Compare `written` and `read_back` in blocks of `block_size` bytes.
+    pub fn analyze(written: &[u8], read_back: &[u8], block_size: usize) -> Self {
+        let blocks = BlockDiff::compare(written, read_back, block_size);
+
+        let tail = match read_back.len().cmp(&written.len()) {
+            std::cmp::Ordering::Equal => Tail::SameLength,
+            std::cmp::Ordering::Greater => Tail::Extra {
+                len: read_back.len() - written.len(),
+                fill: fill_byte(&read_back[written.len()..]),
+            },
+            std::cmp::Ordering::Less => Tail::Missing(written.len() - read_back.len()),
+        };
+
+        let mut index = None;
+        let mut fill_blocks = vec![];
+        let mut relocations = vec![];
+        let written_blocks = written.len().div_ceil(block_size);
+        for i in blocks.changed.iter().flat_map(|run| run.clone()) {
+            // Blocks past the end of the written image are covered by `tail`.
+            if i >= written_blocks {
+                break;
+            }
+
+            let read = block(read_back, i, block_size);
+            if let Some(fill) = fill_byte(read) {
+                fill_blocks.push((i, fill));
+                continue;
+            }
+
+            let index = index.get_or_insert_with(|| ContentIndex::new(read_back));
+            relocations.push(Relocation {
+                block: i,
+                found_at: index.find(block(written, i, block_size), i * block_size),
+            });
+        }
+
+        Self {
+            blocks,
+            tail,
+            fill_blocks,
+            relocations,
+        }
+    }
+
+    /// How far a relocated block moved, in bytes, if it was found.
+    pub fn shift(&self, relocation: &Relocation) -> Option<i64> {
+        let original = relocation.block * self.blocks.block_size;
+        relocation
+            .found_at
+            .map(|found_at| found_at as i64 - original as i64)
+    }
+
+    /// The shift shared by every relocated block, if at least one was found and all those found
+    /// moved by the same amount.
+    pub fn consistent_shift(&self) -> Option<i64> {
+        let mut shifts = self.relocations.iter().filter_map(|r| self.shift(r));
+        let first = shifts.next()?;
+        shifts.all(|shift| shift == first).then_some(first)
+    }
+}
+
+/// The byte `data` consists of, if it's nonempty and a single repeated byte.
+fn fill_byte(data: &[u8]) -> Option<u8> {
+    let (&first, rest) = data.split_first()?;
+    rest.iter().all(|&b| b == first).then_some(first)
+}
+
+/// An index of every offset in a byte string by the 8 bytes starting there, for finding where
+/// blocks of another byte string occur in it without comparing against every offset.
+struct ContentIndex<'a> {
+    data: &'a [u8],
+    offsets: Vec<(u64, usize)>,
+}
+
+impl<'a> ContentIndex<'a> {
+    const KEY_LEN: usize = 8;
+    /// Most offsets with a matching key to check before giving up. Keys repeat a lot in padding,
+    /// but blocks of padding are handled separately.
+    const MAX_CANDIDATES: usize = 256;
+
+    fn new(data: &'a [u8]) -> Self {
+        let mut offsets: Vec<_> = data
+            .windows(Self::KEY_LEN)
+            .enumerate()
+            .map(|(offset, window)| (Self::key(window), offset))
+            .collect();
+        offsets.sort_unstable();
+        Self { data, offsets }
+    }
+
+    fn key(bytes: &[u8]) -> u64 {
+        u64::from_le_bytes(bytes[..Self::KEY_LEN].try_into().unwrap())
+    }
+
+    /// Find where `needle` occurs in the data, preferring the occurrence closest to `near`.
+    fn find(&self, needle: &[u8], near: usize) -> Option<usize> {
+        if needle.len() < Self::KEY_LEN {
+            return None;
+        }
+
+        let key = Self::key(needle);
+        let start = self.offsets.partition_point(|&(k, _)| k < key);
+        self.offsets[start..]
+            .iter()
+            .take_while(|&&(k, _)| k == key)
+            .take(Self::MAX_CANDIDATES)
+            .map(|&(_, offset)| offset)
+            .filter(|&offset| self.data[offset..].starts_with(needle))
+            .min_by_key(|&offset| offset.abs_diff(near))
+    }
+}
+
+/// Get block `i` of `data`, which is short or empty if `data` ends before the block does.
+fn block(data: &[u8], i: usize, block_size: usize) -> &[u8] {
+    let start = (i * block_size).min(data.len());
+    let end = (start + block_size).min(data.len());
+    &data[start..end]
+}
+
+/// Embedded strings that appear in only one of two byte strings, each with its offset.
+#[derive(Debug)]
+pub struct StringChanges<'a> {
+    /// Strings only in the old byte string.
+    pub removed: Vec<(usize, &'a str)>,
+    /// Strings only in the new byte string.
+    pub added: Vec<(usize, &'a str)>,
+}
+
+/// Find the embedded strings (as found by [printable_strings]) that appear in only one of `old`
+/// and `new`, regardless of where, in the order they appear.
+pub fn changed_strings<'a>(old: &'a [u8], new: &'a [u8], min_len: usize) -> StringChanges<'a> {
+    let old_strings = printable_strings(old, min_len);
+    let new_strings = printable_strings(new, min_len);
+
+    let only_in = |a: &[(usize, &'a str)], b: &[(usize, &'a str)]| {
+        let b: HashSet<_> = b.iter().map(|(_, s)| s).collect();
+        a.iter().filter(|(_, s)| !b.contains(s)).copied().collect()
+    };
+    StringChanges {
+        removed: only_in(&old_strings, &new_strings),
+        added: only_in(&new_strings, &old_strings),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_runs_of_changed_blocks() {
+        let old = [0u8; 40];
+        let mut new = old;
+        new[5] = 1; // Block 1
+        new[9] = 1; // Block 2
+        new[35] = 1; // Block 8
+        let diff = BlockDiff::compare(&old, &new, 4);
+        assert_eq!(diff.total_blocks, 10);
+        assert_eq!(diff.changed, [1..3, 8..9]);
+        assert_eq!(diff.changed_blocks(), 3);
+        assert_eq!(diff.percent_changed(), 30.0);
+        assert_eq!(diff.byte_range(&diff.changed[0]), 4..12);
+    }
+
+    #[test]
+    fn blocks_past_the_shorter_input_differ() {
+        let diff = BlockDiff::compare(b"abcdefgh", b"abcdefghij", 4);
+        assert_eq!(diff.total_blocks, 3);
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0], 2..3);
+
+        // A short final block only matches a block of the same length.
+        let diff = BlockDiff::compare(b"abcdef", b"abcdefg", 4);
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0], 1..2);
+    }
+
+    #[test]
+    fn identical_and_empty_inputs_have_no_changes() {
+        let diff = BlockDiff::compare(b"same", b"same", 3);
+        assert!(diff.changed.is_empty());
+        assert_eq!(diff.percent_changed(), 0.0);
+
+        let diff = BlockDiff::compare(b"", b"", 4);
+        assert_eq!(diff.total_blocks, 0);
+        assert_eq!(diff.percent_changed(), 0.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "block size must be nonzero")]
+    fn zero_block_size_panics() {
+        BlockDiff::compare(b"a", b"b", 0);
+    }
+
+    #[test]
+    fn finds_strings_in_only_one_input() {
+        let old = b"\0version 1.3.8\0shared string\0";
+        let new = b"\0shared string\0version 1.3.9\0";
+        let changes = changed_strings(old, new, 5);
+        assert_eq!(changes.removed, [(1, "version 1.3.8")]);
+        assert_eq!(changes.added, [(15, "version 1.3.9")]);
+    }
+
+    /// 64 bytes in which no 8-byte window repeats.
+    fn written() -> Vec<u8> {
+        (0..64u8)
+            .map(|i| i.wrapping_mul(7).wrapping_add(3))
+            .collect()
+    }
+
+    #[test]
+    fn finds_fill_blocks_and_tail() {
+        let written = written();
+        let mut read_back = written.clone();
+        read_back[8..16].fill(0xff);
+        read_back.extend([0xff; 5]);
+
+        let analysis = ReadbackAnalysis::analyze(&written, &read_back, 8);
+        assert_eq!(analysis.fill_blocks, [(1, 0xff)]);
+        assert_eq!(
+            analysis.tail,
+            Tail::Extra {
+                len: 5,
+                fill: Some(0xff)
+            }
+        );
+        // The extra bytes are a changed block too, but only described by the tail.
+        assert_eq!(analysis.blocks.changed_blocks(), 2);
+        assert!(analysis.relocations.is_empty());
+        assert_eq!(analysis.consistent_shift(), None);
+
+        let analysis = ReadbackAnalysis::analyze(&written, &written[..60], 8);
+        assert_eq!(analysis.tail, Tail::Missing(4));
+        let analysis = ReadbackAnalysis::analyze(&written, &written, 8);
+        assert_eq!(analysis.tail, Tail::SameLength);
+        assert!(analysis.blocks.changed.is_empty());
+    }
+
+    #[test]
+    fn finds_relocated_blocks() {
+        let written = written();
+        let mut read_back = b"HDR!".to_vec();
+        read_back.extend(&written);
+
+        let analysis = ReadbackAnalysis::analyze(&written, &read_back, 8);
+        assert_eq!(analysis.relocations.len(), 8);
+        for relocation in &analysis.relocations {
+            assert_eq!(analysis.shift(relocation), Some(4));
+        }
+        assert_eq!(analysis.consistent_shift(), Some(4));
+        assert_eq!(analysis.tail, Tail::Extra { len: 4, fill: None });
+    }
+
+    #[test]
+    fn reports_missing_and_inconsistent_relocations() {
+        let written = written();
+        // Swap blocks 0 and 1, and replace block 2 with something that isn't in the written image.
+        let mut read_back = written.clone();
+        read_back[..8].copy_from_slice(&written[8..16]);
+        read_back[8..16].copy_from_slice(&written[..8]);
+        read_back[16..24].copy_from_slice(b"unknown!");
+
+        let analysis = ReadbackAnalysis::analyze(&written, &read_back, 8);
+        let found: Vec<_> = analysis
+            .relocations
+            .iter()
+            .map(|r| (r.block, r.found_at))
+            .collect();
+        assert_eq!(found, [(0, Some(8)), (1, Some(0)), (2, None)]);
+        assert_eq!(analysis.consistent_shift(), None);
+    }
+}
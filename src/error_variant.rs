@@ -0,0 +1,240 @@
+use std::error::Error;
+
+/// An error type whose variants have stable names, for recording in logs. Unlike `Debug` output,
+/// a name doesn't change when the variant's fields do.
+pub trait ErrorVariant {
+    /// The error's module, type, and variant, like `dfu_file::SuffixError::BadCRC`.
+    fn variant(&self) -> &'static str;
+}
+
+/// Name the innermost error in `error`'s chain of sources that comes from this crate or hidapi.
+/// Outer errors usually just say where the inner one happened, so the innermost is the most
+/// specific.
+pub fn leaf_variant(error: &(dyn Error + 'static)) -> Option<&'static str> {
+    let mut leaf = None;
+    let mut cause = Some(error);
+    while let Some(e) = cause {
+        leaf = variant_of(e).or(leaf);
+        cause = e.source();
+    }
+    leaf
+}
+
+fn variant_of(error: &(dyn Error + 'static)) -> Option<&'static str> {
+    macro_rules! try_types {
+        ($($ty:ty),* $(,)?) => {
+            $(
+                if let Some(e) = error.downcast_ref::<$ty>() {
+                    return Some(e.variant());
+                }
+            )*
+        };
+    }
+
+    try_types!(
+        crate::archive::Error,
+        crate::audit::Error,
+        crate::backup::Error,
+        crate::bundle::Error,
+        crate::catalog::Error,
+        crate::config::Error,
+        crate::device_ids::ParseUsbIdError,
+        crate::dfu_file::Error,
+        crate::dfu_file::SuffixError,
+        crate::dfu_file::SuffixWarning,
+        crate::dfuse::Error,
+        crate::fetch::Error,
+        crate::firmware::Error,
+        crate::history::Error,
+        crate::manifest::Error,
+        crate::policy::Error,
+        crate::protocol::Error,
+        crate::protocol::ProtocolError,
+        crate::version::ParseVersionError,
+    );
+    // hidapi's variants carry platform-specific details, so don't try to tell them apart.
+    error.is::<hidapi::HidError>().then_some("hidapi::HidError")
+}
+
+/// Implement [ErrorVariant] for an enum by listing every variant. Since the match is exhaustive,
+/// adding a variant without naming it here fails to compile.
+macro_rules! variants {
+    ($ty:ty, $name:literal, [$($variant:ident),* $(,)?]) => {
+        impl ErrorVariant for $ty {
+            fn variant(&self) -> &'static str {
+                match self {
+                    $(Self::$variant { .. } => concat!($name, "::", stringify!($variant)),)*
+                }
+            }
+        }
+    };
+}
+
+variants!(
+    crate::archive::Error,
+    "archive::Error",
+    [
+        TooBig,
+        ZipError,
+        NoDfuInArchive,
+        SeveralDfusInArchive,
+        ManifestNotText,
+        BundleError,
+        IsBundle,
+        IoError,
+    ]
+);
+variants!(
+    crate::audit::Error,
+    "audit::Error",
+    [IoError, ParseError, Modified, BrokenChain]
+);
+variants!(crate::backup::Error, "backup::Error", [IoError]);
+variants!(
+    crate::bundle::Error,
+    "bundle::Error",
+    [
+        ParseError,
+        Empty,
+        MissingFile,
+        BadFile,
+        WrongDevice,
+        NoMatch,
+        Ambiguous,
+    ]
+);
+variants!(
+    crate::catalog::Error,
+    "catalog::Error",
+    [XmlError, MissingAttribute, BadProductId]
+);
+variants!(crate::config::Error, "config::Error", [IoError, ParseError]);
+variants!(
+    crate::dfu_file::Error,
+    "dfu_file::Error",
+    [SuffixError, IoError]
+);
+variants!(
+    crate::dfu_file::SuffixError,
+    "dfu_file::SuffixError",
+    [
+        BadSignature,
+        TooOld,
+        FileTooShort,
+        SuffixTooShort,
+        SuffixTooLong,
+        ExtensionTooLong,
+        Strict,
+        BadCRC,
+    ]
+);
+variants!(
+    crate::dfu_file::SuffixWarning,
+    "dfu_file::SuffixWarning",
+    [
+        WildcardId,
+        LongSuffix,
+        UnexpectedDfuVersion,
+        NoReleaseNumber,
+        TinyPayload,
+    ]
+);
+variants!(
+    crate::dfuse::Error,
+    "dfuse::Error",
+    [BadSignature, Truncated, SizeMismatch]
+);
+variants!(
+    crate::fetch::Error,
+    "fetch::Error",
+    [
+        HttpError,
+        NotText,
+        CatalogError,
+        BadImage,
+        WrongDevice,
+        UnsafePath,
+        IoError,
+    ]
+);
+variants!(
+    crate::firmware::Error,
+    "firmware::Error",
+    [IoError, ParseError, DfuFileError]
+);
+variants!(
+    crate::history::Error,
+    "history::Error",
+    [IoError, ParseError]
+);
+variants!(
+    crate::manifest::Error,
+    "manifest::Error",
+    [IoError, ParseError, BadHash]
+);
+variants!(
+    crate::policy::Error,
+    "policy::Error",
+    [IoError, ParseError, NoCriteria]
+);
+variants!(
+    crate::protocol::Error,
+    "protocol::Error",
+    [ProtocolError, DeviceIoError, FileIoError]
+);
+variants!(
+    crate::protocol::ProtocolError,
+    "protocol::ProtocolError",
+    [
+        UnknownState,
+        UnknownStatus,
+        ErrorStatus,
+        UnexpectedState,
+        BadInitialState,
+        FileTooLarge,
+        InvalidString,
+        ReportTooShort,
+    ]
+);
+
+impl ErrorVariant for crate::device_ids::ParseUsbIdError {
+    fn variant(&self) -> &'static str {
+        "device_ids::ParseUsbIdError"
+    }
+}
+
+impl ErrorVariant for crate::version::ParseVersionError {
+    fn variant(&self) -> &'static str {
+        "version::ParseVersionError"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dfu_file::{self, SuffixError};
+
+    #[test]
+    fn names_innermost_error() {
+        let error = crate::firmware::Error::DfuFileError {
+            source: dfu_file::Error::SuffixError(SuffixError::BadCRC {
+                expected: 1,
+                actual: 2,
+            }),
+            path: "x.dfu".into(),
+        };
+        assert_eq!(leaf_variant(&error), Some("dfu_file::SuffixError::BadCRC"));
+    }
+
+    #[test]
+    fn ignores_foreign_errors() {
+        let error = crate::history::Error::IoError {
+            source: std::io::ErrorKind::NotFound.into(),
+            path: "history.toml".into(),
+        };
+        assert_eq!(leaf_variant(&error), Some("history::Error::IoError"));
+
+        let error = std::io::Error::from(std::io::ErrorKind::NotFound);
+        assert_eq!(leaf_variant(&error), None);
+    }
+}
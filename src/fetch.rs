@@ -0,0 +1,429 @@
+use crate::catalog::{self, Index, IndexImage, Lookup, LookupProduct};
+use crate::device_ids::UsbId;
+use crate::dfu_file::{self, DfuFile};
+use std::path::{Component, Path, PathBuf};
+use thiserror::Error;
+
+/// Bose's official download server.
+pub const DEFAULT_SERVER: &str = "https://downloads.bose.com";
+
+/// Largest file we're willing to download by default. Bose's firmware images are a few megabytes at
+/// most.
+pub const MAX_DOWNLOAD_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Downloads catalog files and firmware images from a server laid out like Bose's download server
+/// and saves them in a local cache laid out the same way, so that the cache can be used as a
+/// mirror by [catalog] functions.
+///
+/// Bose's `lookup.xml` refers to each `index.xml` by absolute URL, but only the path part of that
+/// URL is used: every file is fetched from the configured base URL. That lets the fetcher work
+/// against mirrors and local stand-ins for Bose's server.
+pub struct Fetcher {
+    base_url: String,
+    cache_dir: PathBuf,
+    max_size: u64,
+    agent: ureq::Agent,
+}
+
+impl Fetcher {
+    /// Create a fetcher that downloads from `base_url` (e.g. [DEFAULT_SERVER]) into `cache_dir`,
+    /// refusing files bigger than [MAX_DOWNLOAD_SIZE].
+    pub fn new(base_url: &str, cache_dir: &Path) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            cache_dir: cache_dir.to_owned(),
+            max_size: MAX_DOWNLOAD_SIZE,
+            agent: ureq::Agent::new_with_defaults(),
+        }
+    }
+
+    /// Refuse to download files bigger than `max_size` bytes, instead of [MAX_DOWNLOAD_SIZE].
+    pub fn with_max_size(self, max_size: u64) -> Self {
+        Self { max_size, ..self }
+    }
+
+    /// Where downloaded files are cached by default: `bose-dfu` inside the OS's cache directory.
+    pub fn default_cache_dir() -> Option<PathBuf> {
+        dirs::cache_dir().map(|d| d.join("bose-dfu"))
+    }
+
+    /// The directory downloaded files are cached in.
+    pub fn cache_dir(&self) -> &Path {
+        &self.cache_dir
+    }
+
+    /// Download and cache `lookup.xml`.
+    pub fn lookup(&self) -> Result<Lookup, Error> {
+        let (url, xml) = self.fetch_text("lookup.xml")?;
+        let lookup = Lookup::parse(&xml).map_err(|source| Error::CatalogError { source, url })?;
+        self.store("lookup.xml", xml.as_bytes())?;
+        Ok(lookup)
+    }
+
+    /// Download and cache the `index.xml` for a product listed in `lookup.xml`.
+    pub fn index(&self, product: &LookupProduct) -> Result<Index, Error> {
+        let path = product.index_path();
+        let (url, xml) = self.fetch_text(path)?;
+        let index = Index::parse(&xml).map_err(|source| Error::CatalogError { source, url })?;
+        self.store(path, xml.as_bytes())?;
+        Ok(index)
+    }
+
+    /// Get a firmware image listed in a product's `index.xml`, downloading it unless a valid copy
+    /// is already cached. Downloaded images are only cached if they parse as DFU files, have a valid
+    /// CRC, and are meant for a device with the given DFU-mode USB ID. Returns where in the cache
+    /// the image is and whether it had to be downloaded.
+    pub fn image(
+        &self,
+        product: &LookupProduct,
+        image: &IndexImage,
+        dfu_id: UsbId,
+    ) -> Result<(PathBuf, bool), Error> {
+        if image.filename.contains('/') {
+            return Err(Error::UnsafePath(image.filename.clone()));
+        }
+        let path = match product.directory() {
+            "" => image.filename.clone(),
+            dir => format!("{dir}/{}", image.filename),
+        };
+
+        let local = self.local_path(&path)?;
+        if let Ok(data) = std::fs::read(&local)
+            && verify_image(&data, dfu_id, &path).is_ok()
+        {
+            return Ok((local, false));
+        }
+
+        let (url, data) = self.fetch(&path)?;
+        verify_image(&data, dfu_id, &url)?;
+        self.store(&path, &data)?;
+        Ok((local, true))
+    }
+
+    fn fetch(&self, path: &str) -> Result<(String, Vec<u8>), Error> {
+        self.local_path(path)?;
+        let url = format!("{}/{path}", self.base_url);
+        let result = self.agent.get(&url).call().and_then(|mut response| {
+            response
+                .body_mut()
+                .with_config()
+                // ureq refuses a body as soon as it reaches the limit, even if it ends there.
+                .limit(self.max_size.saturating_add(1))
+                .read_to_vec()
+        });
+
+        match result {
+            Ok(data) => Ok((url, data)),
+            Err(source) => Err(Error::HttpError { source, url }),
+        }
+    }
+
+    fn fetch_text(&self, path: &str) -> Result<(String, String), Error> {
+        let (url, data) = self.fetch(path)?;
+        match String::from_utf8(data) {
+            Ok(text) => Ok((url, text)),
+            Err(_) => Err(Error::NotText(url)),
+        }
+    }
+
+    /// Map a server path to a path in the cache, refusing anything that could escape the cache.
+    /// Paths come from downloaded XML files, so they can't be trusted.
+    fn local_path(&self, path: &str) -> Result<PathBuf, Error> {
+        let relative = Path::new(path);
+        let safe = !path.is_empty()
+            && !path.contains('\\')
+            && relative
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)));
+        if !safe {
+            return Err(Error::UnsafePath(path.to_owned()));
+        }
+
+        Ok(self.cache_dir.join(relative))
+    }
+
+    /// Atomically write a downloaded file into the cache.
+    fn store(&self, path: &str, data: &[u8]) -> Result<(), Error> {
+        let local = self.local_path(path)?;
+        let io_err = |source| Error::IoError {
+            source,
+            path: local.clone(),
+        };
+
+        if let Some(dir) = local.parent() {
+            std::fs::create_dir_all(dir).map_err(io_err)?;
+        }
+
+        let mut partial = local.clone().into_os_string();
+        partial.push(".part");
+        let result =
+            std::fs::write(&partial, data).and_then(|()| std::fs::rename(&partial, &local));
+        if result.is_err() {
+            // Don't leave a half-written file behind. It's ignored anyway, but it would pile up.
+            let _ = std::fs::remove_file(&partial);
+        }
+        result.map_err(io_err)
+    }
+}
+
+/// Check that `data` is an intact DFU file meant for a device with the given DFU-mode USB ID.
+/// `url` is only used for error messages.
+fn verify_image(data: &[u8], dfu_id: UsbId, url: &str) -> Result<(), Error> {
+    let bad_image = |source| Error::BadImage {
+        source,
+        url: url.to_owned(),
+    };
+
+    let file = DfuFile::from_bytes(data).map_err(bad_image)?;
+    file.ensure_valid_crc().map_err(|e| bad_image(e.into()))?;
+    if !file.matches_device(dfu_id) {
+        let suffix = file.suffix();
+        return Err(Error::WrongDevice {
+            url: url.to_owned(),
+            file_id: format!("{:04x}:{:04x}", suffix.vendor_id, suffix.product_id),
+            dfu_id,
+        });
+    }
+
+    Ok(())
+}
+
+/// Errors that can happen while fetching firmware.
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("failed to download {url}")]
+    HttpError { source: ureq::Error, url: String },
+
+    #[error("{0} is not a text file")]
+    NotText(String),
+
+    #[error("invalid catalog file {url}")]
+    CatalogError { source: catalog::Error, url: String },
+
+    #[error("downloaded file {url} is not a valid firmware image")]
+    BadImage {
+        source: dfu_file::Error,
+        url: String,
+    },
+
+    #[error("downloaded file {url} is for USB ID {file_id}, not {dfu_id}")]
+    WrongDevice {
+        url: String,
+        file_id: String,
+        dfu_id: UsbId,
+    },
+
+    #[error("refusing to use unsafe path {0:?} from catalog file")]
+    UnsafePath(String),
+
+    #[error("failed to write {}", .path.display())]
+    IoError {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dfu_file::{OptionalId, SuffixInfo};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    const DFU_ID: UsbId = UsbId {
+        vid: 0x05a7,
+        pid: 0x400d,
+    };
+
+    /// Serve each path in `routes` with the given status and body from a local stand-in for Bose's
+    /// server, returning its base URL. Unknown paths get a 404.
+    fn serve(routes: Vec<(&'static str, u16, Vec<u8>)>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { continue };
+                let mut request = String::new();
+                let mut reader = BufReader::new(&stream);
+                reader.read_line(&mut request).unwrap();
+                let mut header = String::new();
+                while reader.read_line(&mut header).is_ok_and(|n| n > 2) {
+                    header.clear();
+                }
+
+                let path = request.split(' ').nth(1).unwrap_or("");
+                let (status, body) = routes
+                    .iter()
+                    .find(|(p, _, _)| *p == path)
+                    .map_or((404, &[][..]), |(_, status, body)| (*status, &body[..]));
+                // The client may hang up early, e.g. when a body is too big, so ignore errors.
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {status} X\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )
+                .and_then(|()| stream.write_all(body));
+            }
+        });
+
+        base_url
+    }
+
+    /// Create an empty directory for a test to use as a cache.
+    fn cache_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("bose-dfu-fetch-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// List every file under `dir`, relative to it.
+    fn files_in(dir: &Path) -> Vec<PathBuf> {
+        let mut found = vec![];
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                found.extend(
+                    files_in(&path)
+                        .into_iter()
+                        .map(|p| Path::new(path.file_name().unwrap()).join(p)),
+                );
+            } else {
+                found.push(PathBuf::from(path.file_name().unwrap()));
+            }
+        }
+        found
+    }
+
+    fn product() -> LookupProduct {
+        LookupProduct {
+            dfu_pid: DFU_ID.pid,
+            index_url: "https://downloads.bose.com/lando/index.xml".to_owned(),
+        }
+    }
+
+    fn image(filename: &str) -> IndexImage {
+        IndexImage {
+            filename: filename.to_owned(),
+            revision: None,
+        }
+    }
+
+    fn valid_dfu() -> Vec<u8> {
+        let suffix = SuffixInfo::new(DFU_ID.vid.into(), DFU_ID.pid.into(), OptionalId(None));
+        let mut data = vec![];
+        crate::dfu_file::write(&mut data, b"firmware", &suffix).unwrap();
+        data
+    }
+
+    #[test]
+    fn local_path_rejects_escapes() {
+        let fetcher = Fetcher::new("http://unused", Path::new("/cache"));
+        for path in [
+            "",
+            "../x.dfu",
+            "/etc/passwd",
+            "a/../../x.dfu",
+            "a\\..\\x.dfu",
+            "./x.dfu",
+        ] {
+            assert!(
+                matches!(fetcher.local_path(path), Err(Error::UnsafePath(_))),
+                "{path:?} should be rejected"
+            );
+        }
+        assert_eq!(
+            fetcher.local_path("lando/x.dfu").unwrap(),
+            Path::new("/cache/lando/x.dfu")
+        );
+
+        let result = fetcher.image(&product(), &image("../x.dfu"), DFU_ID);
+        assert!(matches!(result, Err(Error::UnsafePath(_))));
+    }
+
+    #[test]
+    fn valid_image_is_cached() {
+        let base_url = serve(vec![("/lando/x.dfu", 200, valid_dfu())]);
+        let dir = cache_dir("valid");
+        let fetcher = Fetcher::new(&base_url, &dir);
+
+        let (path, downloaded) = fetcher.image(&product(), &image("x.dfu"), DFU_ID).unwrap();
+        assert!(downloaded);
+        assert_eq!(path, dir.join("lando/x.dfu"));
+        assert_eq!(std::fs::read(&path).unwrap(), valid_dfu());
+
+        let (_, downloaded) = fetcher.image(&product(), &image("x.dfu"), DFU_ID).unwrap();
+        assert!(!downloaded);
+        assert_eq!(files_in(&dir), [Path::new("lando/x.dfu")]);
+    }
+
+    #[test]
+    fn oversized_download_is_refused() {
+        const MAX_SIZE: u64 = 1024;
+        let mut fits = vec![];
+        let suffix = SuffixInfo::new(0x05a7.into(), 0x400d.into(), OptionalId(None));
+        let payload = vec![0; MAX_SIZE as usize - 16];
+        crate::dfu_file::write(&mut fits, &payload, &suffix).unwrap();
+        let base_url = serve(vec![
+            ("/lando/fits.dfu", 200, fits),
+            ("/lando/big.dfu", 200, vec![0; MAX_SIZE as usize + 1]),
+        ]);
+        let dir = cache_dir("oversized");
+        let fetcher = Fetcher::new(&base_url, &dir).with_max_size(MAX_SIZE);
+
+        let result = fetcher.image(&product(), &image("big.dfu"), DFU_ID);
+        assert!(matches!(result, Err(Error::HttpError { .. })), "{result:?}");
+        assert!(files_in(&dir).is_empty());
+
+        // Exactly the limit is fine.
+        fetcher
+            .image(&product(), &image("fits.dfu"), DFU_ID)
+            .unwrap();
+        assert_eq!(files_in(&dir), [Path::new("lando/fits.dfu")]);
+    }
+
+    #[test]
+    fn failed_or_invalid_download_leaves_nothing() {
+        let other_id = SuffixInfo::new(0x05a7.into(), 0x40fe.into(), OptionalId(None));
+        let mut wrong_device = vec![];
+        crate::dfu_file::write(&mut wrong_device, b"firmware", &other_id).unwrap();
+
+        let base_url = serve(vec![
+            ("/lando/garbage.dfu", 200, b"not a DFU file".to_vec()),
+            ("/lando/wrong.dfu", 200, wrong_device),
+            ("/lando/error.dfu", 500, vec![]),
+        ]);
+        let dir = cache_dir("invalid");
+        let fetcher = Fetcher::new(&base_url, &dir);
+
+        let result = fetcher.image(&product(), &image("garbage.dfu"), DFU_ID);
+        assert!(matches!(result, Err(Error::BadImage { .. })), "{result:?}");
+        let result = fetcher.image(&product(), &image("wrong.dfu"), DFU_ID);
+        assert!(
+            matches!(result, Err(Error::WrongDevice { .. })),
+            "{result:?}"
+        );
+        let result = fetcher.image(&product(), &image("error.dfu"), DFU_ID);
+        assert!(matches!(result, Err(Error::HttpError { .. })), "{result:?}");
+        let result = fetcher.image(&product(), &image("missing.dfu"), DFU_ID);
+        assert!(matches!(result, Err(Error::HttpError { .. })), "{result:?}");
+
+        assert!(files_in(&dir).is_empty());
+    }
+
+    #[test]
+    fn failed_store_removes_partial_file() {
+        let dir = cache_dir("store");
+        let fetcher = Fetcher::new("http://unused", &dir);
+
+        // A directory in the way makes the final rename fail after the partial file is written.
+        std::fs::create_dir_all(dir.join("lando/x.dfu/blocker")).unwrap();
+        let result = fetcher.store("lando/x.dfu", &valid_dfu());
+        assert!(matches!(result, Err(Error::IoError { .. })), "{result:?}");
+        assert!(!dir.join("lando/x.dfu.part").exists());
+        assert!(files_in(&dir).is_empty());
+    }
+}
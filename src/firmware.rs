@@ -0,0 +1,264 @@
+use crate::device_ids::UsbId;
+use crate::dfu_file::{self, DfuFile, OptionalId};
+use crate::version::FirmwareVersion;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Name of the file, inside a library's directory, that the library's index is saved to.
+pub const INDEX_FILE: &str = "index.toml";
+
+/// A directory of firmware files, along with an index of what each one contains. Files are found
+/// by recursively scanning the directory for files ending in `.dfu`, so a cache populated by
+/// [crate::fetch::Fetcher] can be used as a library.
+///
+/// Scanning hashes every file, so the result is saved to [INDEX_FILE] to allow later checking that
+/// no file has changed since.
+#[derive(Debug)]
+pub struct Library {
+    pub dir: PathBuf,
+    pub entries: Vec<FirmwareEntry>,
+}
+
+/// What a library knows about one firmware file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FirmwareEntry {
+    /// Location of the file, relative to the library's directory.
+    pub path: PathBuf,
+    pub vendor_id: OptionalId,
+    pub product_id: OptionalId,
+    pub release_number: OptionalId,
+    pub crc: u32,
+    /// SHA-256 hash of the whole file, as a lowercase hex string.
+    pub sha256: String,
+    pub size: u64,
+}
+
+/// The result of checking a library entry against the file it describes.
+#[derive(Debug)]
+pub enum EntryStatus {
+    /// The file is unchanged and its CRC is valid.
+    Ok,
+    Missing,
+    /// The file's contents differ from when it was indexed.
+    Modified,
+    /// The file can no longer be read or parsed.
+    Unreadable(Error),
+}
+
+/// On-disk format of [INDEX_FILE].
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IndexFile {
+    #[serde(default, rename = "firmware")]
+    entries: Vec<FirmwareEntry>,
+}
+
+impl Library {
+    /// Where the library lives by default: `bose-dfu/firmware` inside the OS's data directory.
+    pub fn default_dir() -> Option<PathBuf> {
+        dirs::data_dir().map(|d| d.join("bose-dfu").join("firmware"))
+    }
+
+    /// Scan `dir` for firmware files and index them. Files that can't be parsed or have an invalid
+    /// CRC are left out of the library and returned alongside it.
+    pub fn scan(dir: &Path) -> Result<(Self, Vec<(PathBuf, Error)>), Error> {
+        let mut files = vec![];
+        find_dfu_files(dir, &mut files)?;
+        files.sort();
+
+        let mut entries = vec![];
+        let mut rejected = vec![];
+        for file in files {
+            let relative = file.strip_prefix(dir).unwrap_or(&file).to_owned();
+            match FirmwareEntry::from_file(&file, &relative) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => rejected.push((relative, e)),
+            }
+        }
+
+        let library = Self {
+            dir: dir.to_owned(),
+            entries,
+        };
+        Ok((library, rejected))
+    }
+
+    /// Load the index saved by [Library::save] in `dir`, without rescanning.
+    pub fn load(dir: &Path) -> Result<Self, Error> {
+        let path = dir.join(INDEX_FILE);
+        let text = std::fs::read_to_string(&path).map_err(|source| Error::IoError {
+            source,
+            path: path.clone(),
+        })?;
+        let index: IndexFile =
+            toml::from_str(&text).map_err(|source| Error::ParseError { source, path })?;
+
+        Ok(Self {
+            dir: dir.to_owned(),
+            entries: index.entries,
+        })
+    }
+
+    /// Load the index in `dir` if there is one, or scan `dir` if not. Files rejected by the scan are
+    /// left out.
+    pub fn open(dir: &Path) -> Result<Self, Error> {
+        match dir.join(INDEX_FILE).exists() {
+            true => Self::load(dir),
+            false => Self::scan(dir).map(|(library, _)| library),
+        }
+    }
+
+    /// Save the library's index to [INDEX_FILE] in its directory.
+    pub fn save(&self) -> Result<(), Error> {
+        let index = IndexFile {
+            entries: self.entries.clone(),
+        };
+        let text = toml::to_string(&index).expect("index should always serialize");
+
+        let path = self.dir.join(INDEX_FILE);
+        std::fs::write(&path, text).map_err(|source| Error::IoError { source, path })
+    }
+
+    /// Find all files that a device with the given DFU-mode USB ID would accept, newest first.
+    /// Includes files with wildcard IDs.
+    pub fn find(&self, dfu_id: UsbId) -> Vec<&FirmwareEntry> {
+        let mut found: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|e| e.vendor_id.matches(dfu_id.vid) && e.product_id.matches(dfu_id.pid))
+            .collect();
+        found.sort_by(|a, b| match (a.firmware_version(), b.firmware_version()) {
+            (Some(a), Some(b)) => b.compare(&a),
+            (a, b) => b.is_some().cmp(&a.is_some()),
+        });
+        found
+    }
+
+    /// Find the file with the given version for a device with the given DFU-mode USB ID. Files
+    /// with wildcard IDs are only considered if there's no exact match.
+    pub fn find_version(&self, dfu_id: UsbId, version: &FirmwareVersion) -> Option<&FirmwareEntry> {
+        let candidates: Vec<_> = self
+            .find(dfu_id)
+            .into_iter()
+            .filter(|e| {
+                e.firmware_version()
+                    .is_some_and(|v| v.is_same_release(version))
+            })
+            .collect();
+
+        let exact = |e: &&&FirmwareEntry| e.vendor_id.0.is_some() && e.product_id.0.is_some();
+        candidates
+            .iter()
+            .find(exact)
+            .or(candidates.first())
+            .copied()
+    }
+
+    /// Full path to an entry's file.
+    pub fn path_of(&self, entry: &FirmwareEntry) -> PathBuf {
+        self.dir.join(&entry.path)
+    }
+
+    /// Check that an entry still describes the file it was created from.
+    pub fn verify(&self, entry: &FirmwareEntry) -> EntryStatus {
+        let path = self.path_of(entry);
+        if !path.exists() {
+            return EntryStatus::Missing;
+        }
+
+        match FirmwareEntry::from_file(&path, &entry.path) {
+            Ok(current) if current.sha256 == entry.sha256 => EntryStatus::Ok,
+            Ok(_) => EntryStatus::Modified,
+            Err(e) => EntryStatus::Unreadable(e),
+        }
+    }
+}
+
+impl FirmwareEntry {
+    /// Read, parse, and hash the firmware file at `path`, recording it as `relative`. Fails if the
+    /// file has an invalid CRC.
+    pub fn from_file(path: &Path, relative: &Path) -> Result<Self, Error> {
+        let data = std::fs::read(path).map_err(|source| Error::IoError {
+            source,
+            path: path.to_owned(),
+        })?;
+
+        let dfu_err = |source| Error::DfuFileError {
+            source,
+            path: path.to_owned(),
+        };
+        let file = DfuFile::from_bytes(&data).map_err(dfu_err)?;
+        file.ensure_valid_crc().map_err(|e| dfu_err(e.into()))?;
+        let suffix = file.suffix();
+
+        Ok(Self {
+            path: relative.to_owned(),
+            vendor_id: suffix.vendor_id,
+            product_id: suffix.product_id,
+            release_number: suffix.release_number,
+            crc: suffix.expected_crc,
+            sha256: sha256_hex(&data),
+            size: data.len() as u64,
+        })
+    }
+
+    /// The firmware version this file claims to contain, decoded from its release number.
+    pub fn firmware_version(&self) -> Option<FirmwareVersion> {
+        self.release_number.0.and_then(FirmwareVersion::from_bcd)
+    }
+}
+
+/// Hash `data` with SHA-256, returning the hash as a lowercase hex string.
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn find_dfu_files(dir: &Path, found: &mut Vec<PathBuf>) -> Result<(), Error> {
+    let io_err = |source| Error::IoError {
+        source,
+        path: dir.to_owned(),
+    };
+
+    for entry in std::fs::read_dir(dir).map_err(io_err)? {
+        let entry = entry.map_err(io_err)?;
+        let path = entry.path();
+        if entry.file_type().map_err(io_err)?.is_dir() {
+            find_dfu_files(&path, found)?;
+        } else if path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("dfu"))
+        {
+            found.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Errors that can happen while scanning or loading a firmware library.
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("failed to access {}", .path.display())]
+    IoError {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+
+    #[error("invalid library index {}", .path.display())]
+    ParseError {
+        source: toml::de::Error,
+        path: PathBuf,
+    },
+
+    #[error("failed to load firmware file {}", .path.display())]
+    DfuFileError {
+        source: dfu_file::Error,
+        path: PathBuf,
+    },
+}
@@ -0,0 +1,305 @@
+use crate::device_ids::UsbId;
+use crate::version::FirmwareVersion;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use thiserror::Error;
+
+/// A record of every firmware update bose-dfu has performed, read from a TOML file containing a
+/// list of `[[flash]]` tables, oldest first.
+#[derive(Debug, Default)]
+pub struct History {
+    pub entries: Vec<HistoryEntry>,
+}
+
+/// One successful firmware update.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HistoryEntry {
+    /// When the update finished, in RFC 3339 format.
+    pub timestamp: String,
+    /// USB serial number of the updated device.
+    pub serial: Option<String>,
+    /// DFU-mode USB ID of the updated device.
+    pub usb_id: UsbId,
+    /// Firmware version the device reported before the update, if it was in normal mode to ask.
+    pub previous_version: Option<String>,
+    /// Firmware version the update installed, if known.
+    pub new_version: Option<String>,
+    /// SHA-256 hash of the firmware file written, as a lowercase hex string. For bundles, this is
+    /// the hash of the file picked from the bundle.
+    pub sha256: String,
+    /// Absolute path of the firmware file written, unless it was read from standard input.
+    pub file: Option<PathBuf>,
+    /// Whether the firmware was read back and checked after writing, if that was asked for.
+    pub verification: Option<Verification>,
+}
+
+/// The result of asking to verify a download by reading the firmware back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verification {
+    /// The device read back the firmware that was written.
+    Verified,
+    /// It's not known how what the device reads back relates to what was written, so nothing was
+    /// checked.
+    Unverified,
+}
+
+/// On-disk format of the history file.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HistoryFile {
+    #[serde(default, rename = "flash")]
+    entries: Vec<HistoryEntry>,
+}
+
+impl HistoryEntry {
+    /// Describe an update that just finished.
+    pub fn new(
+        serial: Option<&str>,
+        usb_id: UsbId,
+        previous_version: Option<&str>,
+        new_version: Option<&FirmwareVersion>,
+        sha256: &str,
+        file: Option<&Path>,
+    ) -> Self {
+        Self {
+            timestamp: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            serial: serial.map(str::to_owned),
+            usb_id,
+            previous_version: previous_version.map(str::to_owned),
+            new_version: new_version.map(FirmwareVersion::to_string),
+            sha256: sha256.to_owned(),
+            file: file.map(Path::to_owned),
+            verification: None,
+        }
+    }
+
+    /// Whether this update installed the given version.
+    pub fn installed(&self, version: &FirmwareVersion) -> bool {
+        self.new_version
+            .as_ref()
+            .and_then(|v| v.parse::<FirmwareVersion>().ok())
+            .is_some_and(|v| v.is_same_release(version))
+    }
+}
+
+impl Display for Verification {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Verification::Verified => write!(f, "verified"),
+            Verification::Unverified => write!(f, "unverified"),
+        }
+    }
+}
+
+impl History {
+    /// Where the history is kept by default: `bose-dfu/history.toml` inside the OS's data directory.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::data_dir().map(|d| d.join("bose-dfu").join("history.toml"))
+    }
+
+    /// Load the history file at `path`, or return an empty history if there isn't one.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(source) => {
+                return Err(Error::IoError {
+                    source,
+                    path: path.to_owned(),
+                });
+            }
+        };
+
+        let file: HistoryFile = toml::from_str(&text).map_err(|source| Error::ParseError {
+            source,
+            path: path.to_owned(),
+        })?;
+        Ok(Self {
+            entries: file.entries,
+        })
+    }
+
+    /// Add an entry to the end of the history file at `path`, creating it if necessary. Appends
+    /// are serialized with an exclusive lock on `<path>.lock`, so concurrent ones don't lose
+    /// entries.
+    pub fn append(path: &Path, entry: HistoryEntry) -> Result<(), Error> {
+        let io_err = |source| Error::IoError {
+            source,
+            path: path.to_owned(),
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(io_err)?;
+        }
+
+        // The history file itself is replaced on every append, so it can't hold the lock.
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(sibling(path, ".lock"))
+            .map_err(io_err)?;
+        lock.lock().map_err(io_err)?;
+
+        let mut history = Self::load(path)?;
+        history.entries.push(entry);
+
+        let file = HistoryFile {
+            entries: history.entries,
+        };
+        let text = toml::to_string(&file).expect("history should always serialize");
+
+        // Write to a temporary file first, so that a crash can't leave the history truncated.
+        let partial = sibling(path, ".part");
+        std::fs::write(&partial, text).map_err(io_err)?;
+        std::fs::rename(&partial, path).map_err(io_err)
+    }
+
+    /// The most recent update of the device with the given USB serial number.
+    pub fn last_for(&self, serial: &str) -> Option<&HistoryEntry> {
+        self.entries
+            .iter()
+            .rfind(|e| e.serial.as_deref() == Some(serial))
+    }
+
+    /// Updates of devices with the given DFU-mode USB ID that installed the given version, newest
+    /// first.
+    pub fn installs_of<'a>(
+        &'a self,
+        usb_id: UsbId,
+        version: &'a FirmwareVersion,
+    ) -> impl Iterator<Item = &'a HistoryEntry> {
+        self.entries
+            .iter()
+            .rev()
+            .filter(move |e| e.usb_id == usb_id && e.installed(version))
+    }
+}
+
+/// `path` with `suffix` added to its file name.
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.to_owned().into_os_string();
+    name.push(suffix);
+    name.into()
+}
+
+/// Errors that can happen while loading or saving the update history.
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("failed to access history file {}", .path.display())]
+    IoError {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+
+    #[error("invalid history file {}", .path.display())]
+    ParseError {
+        source: toml::de::Error,
+        path: PathBuf,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A path for a history file in a fresh directory.
+    fn history_path(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("bose-dfu-history-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir.join("history.toml")
+    }
+
+    fn entry(serial: &str, new_version: &str) -> HistoryEntry {
+        let usb_id = UsbId {
+            vid: 0x05a7,
+            pid: 0x40fe,
+        };
+        let version = new_version.parse().unwrap();
+        HistoryEntry::new(Some(serial), usb_id, None, Some(&version), "ab", None)
+    }
+
+    #[test]
+    fn missing_file_is_empty() {
+        let history = History::load(&history_path("missing")).unwrap();
+        assert!(history.entries.is_empty());
+    }
+
+    #[test]
+    fn appends_in_order() {
+        let path = history_path("order");
+        History::append(&path, entry("A", "1.0.0")).unwrap();
+        History::append(&path, entry("B", "1.0.0")).unwrap();
+        let mut verified = entry("A", "2.0.0");
+        verified.verification = Some(Verification::Unverified);
+        History::append(&path, verified).unwrap();
+
+        let history = History::load(&path).unwrap();
+        assert_eq!(history.entries.len(), 3);
+        assert_eq!(history.entries[0].verification, None);
+        assert_eq!(
+            history.entries[2].verification,
+            Some(Verification::Unverified)
+        );
+        assert_eq!(
+            history.last_for("A").unwrap().new_version.as_deref(),
+            Some("2.0.0")
+        );
+        assert!(history.last_for("C").is_none());
+        assert!(!sibling(&path, ".part").exists());
+    }
+
+    #[test]
+    fn concurrent_appends_keep_every_entry() {
+        let path = history_path("concurrent");
+        std::thread::scope(|scope| {
+            for i in 0..8 {
+                let path = &path;
+                scope.spawn(move || {
+                    for j in 0..5 {
+                        History::append(path, entry(&format!("{i}-{j}"), "1.0.0")).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(History::load(&path).unwrap().entries.len(), 40);
+    }
+
+    #[test]
+    fn finds_installs_newest_first() {
+        let mut history = History::default();
+        let mut first = entry("A", "1.0.0");
+        first.sha256 = "first".to_owned();
+        let mut second = entry("B", "1.0.0");
+        second.sha256 = "second".to_owned();
+        history.entries = vec![first, entry("A", "2.0.0"), second];
+
+        let version = "1.0.0".parse().unwrap();
+        let usb_id = history.entries[0].usb_id;
+        let found: Vec<_> = history
+            .installs_of(usb_id, &version)
+            .map(|e| e.sha256.as_str())
+            .collect();
+        assert_eq!(found, ["second", "first"]);
+
+        let other_id = UsbId { vid: 1, pid: 2 };
+        assert_eq!(history.installs_of(other_id, &version).count(), 0);
+    }
+
+    #[test]
+    fn invalid_file_is_refused() {
+        let path = history_path("invalid");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, "[[flash]]\nbogus = 1\n").unwrap();
+        assert!(matches!(
+            History::append(&path, entry("A", "1.0.0")),
+            Err(Error::ParseError { .. })
+        ));
+    }
+}
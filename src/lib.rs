@@ -4,5 +4,50 @@ pub mod device_ids;
 /// Load and validate firmware update files containing suffixes as defined the DFU spec.
 pub mod dfu_file;
 
+/// Parse the DfuSe extension of the DFU file format used by STM32 bootloaders.
+pub mod dfuse;
+
+/// Read firmware files that may be compressed or inside a zip or tar archive.
+pub mod archive;
+
+/// Read multi-model firmware bundles and pick the file for a particular device from one.
+pub mod bundle;
+
 /// Perform firmware-related operations on a connected Bose USB device using HID reports.
 pub mod protocol;
+
+/// Load user settings, such as device nicknames, from a configuration file.
+pub mod config;
+
+/// Describe which firmware each kind of device should run, for updating many devices at once.
+pub mod policy;
+
+/// Parse and compare firmware version numbers.
+pub mod version;
+
+/// Parse the catalog files Bose's download server uses to list available firmware.
+pub mod catalog;
+
+/// Download firmware and catalog files from Bose's download server into a local cache.
+pub mod fetch;
+
+/// Keep an index of a directory of firmware files, to find firmware for a device by version.
+pub mod firmware;
+
+/// Check firmware files against a manifest of known-good SHA-256 hashes.
+pub mod manifest;
+
+/// Compare two firmware images block by block.
+pub mod diff;
+
+/// Save firmware images read back from devices, along with details of where they came from.
+pub mod backup;
+
+/// Keep a record of firmware updates, so that a device's previous firmware can be reinstalled.
+pub mod history;
+
+/// Keep a tamper-evident log of operations that change a device's state.
+pub mod audit;
+
+/// Give errors stable names, for recording which error an operation failed with.
+pub mod error_variant;
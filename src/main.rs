@@ -1,24 +1,67 @@
-use anyhow::{Context, Result, bail};
+use anyhow::{Context, Result, anyhow, bail};
 use clap::Parser;
 use hidapi::{DeviceInfo, HidApi, HidDevice};
-use log::{info, warn};
+use log::{error, info, warn};
 use rustyline::DefaultEditor;
 use rustyline::error::ReadlineError;
-use std::io::Read;
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::io::{Cursor, IsTerminal, Read, Seek};
 use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
-use bose_dfu::device_ids::{DeviceCompat, DeviceMode, UsbId, identify_device};
-use bose_dfu::dfu_file::parse as parse_dfu_file;
+use bose_dfu::archive::{self, Contents, read_contents};
+use bose_dfu::audit::{AuditDevice, AuditEntry, AuditLog, OTHER_ERROR_VARIANT, Outcome};
+use bose_dfu::backup::{BackupInfo, BackupStore};
+use bose_dfu::bundle::{self, Bundle};
+use bose_dfu::catalog::{Index, Lookup};
+use bose_dfu::config::Config;
+use bose_dfu::device_ids::{
+    BOSE_VID, COMPATIBLE_DEVICES, DeviceCompat, DeviceIds, DeviceMode, ReadbackRule, UsbId,
+    device_names, find_device, find_device_ids, identify_device,
+};
+use bose_dfu::dfu_file::{
+    self as dfu_file, DfuFile, OptionalId, SuffixExtension, SuffixInfo, SuffixWarning, Validation,
+    printable_strings,
+};
+use bose_dfu::dfuse;
+use bose_dfu::diff::{BlockDiff, ReadbackAnalysis, Tail, changed_strings};
+use bose_dfu::error_variant::leaf_variant;
+use bose_dfu::fetch::{DEFAULT_SERVER, Fetcher};
+use bose_dfu::firmware::{EntryStatus, FirmwareEntry, Library, sha256_hex};
+use bose_dfu::history::{History, HistoryEntry, Verification};
+use bose_dfu::manifest::{Manifest, Verdict};
+use bose_dfu::policy::Policy;
 use bose_dfu::protocol::{
-    download, ensure_idle, enter_dfu, leave_dfu, read_info_field, run_tap_command,
+    InfoField, XFER_DATA_SIZE, download, download_with_progress, ensure_idle, enter_dfu, leave_dfu,
+    read_info_field, read_status, run_tap_command, upload,
 };
+use bose_dfu::version::FirmwareVersion;
 
 #[derive(Parser, Debug)]
 #[command(version, about)]
 enum Opt {
     /// List all connected Bose HID devices (vendor ID 0x05a7)
-    List,
+    List {
+        /// Also print each device's OS-specific path, for use with --path
+        #[arg(short, long)]
+        verbose: bool,
+    },
+
+    /// Print identifying details and firmware versions of all connected devices as CSV or JSON
+    Inventory {
+        #[arg(long, value_enum, default_value_t = InventoryFormat::Csv)]
+        format: InventoryFormat,
+
+        /// Also query untested devices over TAP, as `info -f` does, even though they might not
+        /// understand the queries
+        #[arg(short, long)]
+        force: bool,
+    },
 
     /// Get information about a specific device not in DFU mode
     Info {
@@ -49,14 +92,252 @@ enum Opt {
         #[command(flatten)]
         spec: DeviceSpec,
 
+        #[arg(required_unless_present = "release")]
+        file: Option<std::path::PathBuf>,
+
+        /// Write the file with this version from the firmware library instead of a given file
+        #[arg(long, conflicts_with = "file")]
+        release: Option<FirmwareVersion>,
+
+        #[arg(short, long)]
+        wildcard_fw: bool,
+
+        /// Write to every matching device at once instead of requiring exactly one match
+        #[arg(short, long)]
+        all: bool,
+
+        /// Read the firmware back after writing it and check that it matches, if it's known how
+        /// to for the device
+        #[arg(long)]
+        verify: bool,
+
+        /// Read the current firmware from the device and save it before writing
+        #[arg(long)]
+        backup: bool,
+
+        /// Treat the file as a raw firmware image without a DFU suffix, meant for this device
+        /// (given by name, as printed by `file-info`, or by DFU-mode USB ID)
+        #[arg(long, value_name = "DEVICE", conflicts_with = "release")]
+        raw: Option<String>,
+
+        /// Allow writing firmware whose hash isn't listed in the manifest file named in the
+        /// configuration file
+        #[arg(long)]
+        allow_unknown: bool,
+    },
+
+    /// Read firmware back from a device in DFU mode and compare it to the file written to it
+    ///
+    /// Bose devices don't read back exactly what was written, so this is only useful for working
+    /// out how the two differ.
+    UploadDiff {
+        #[command(flatten)]
+        spec: DeviceSpec,
+
+        /// Firmware file last written to the device
+        file: std::path::PathBuf,
+
+        /// Also save the image read from the device to this file
+        #[arg(long)]
+        save: Option<std::path::PathBuf>,
+    },
+
+    /// Put a device into DFU mode, write firmware to it, and take it back out of DFU mode
+    Update {
+        #[command(flatten)]
+        spec: DeviceSpec,
+
+        #[arg(required_unless_present = "release")]
+        file: Option<std::path::PathBuf>,
+
+        /// Write the file with this version from the firmware library instead of a given file
+        #[arg(long, conflicts_with = "file")]
+        release: Option<FirmwareVersion>,
+
+        #[arg(short, long)]
+        wildcard_fw: bool,
+
+        /// Firmware version the file installs, as printed by `info` [default: the file's release
+        /// number]
+        #[arg(long)]
+        fw_version: Option<FirmwareVersion>,
+
+        /// Allow writing firmware whose hash isn't listed in the manifest file named in the
+        /// configuration file
+        #[arg(long)]
+        allow_unknown: bool,
+
+        /// Read the current firmware from the device and save it before writing
+        #[arg(long)]
+        backup: bool,
+
+        #[command(flatten)]
+        checks: VersionChecks,
+    },
+
+    /// Reinstall the firmware a device was running before its last recorded update
+    Rollback {
+        #[command(flatten)]
+        spec: DeviceSpec,
+
+        #[arg(short, long)]
+        wildcard_fw: bool,
+
+        /// Allow writing firmware whose hash isn't listed in the manifest file named in the
+        /// configuration file
+        #[arg(long)]
+        allow_unknown: bool,
+    },
+
+    /// Wait for matching devices to be connected and update any not running the given firmware
+    Watch {
+        #[command(flatten)]
+        spec: DeviceSpec,
+
         file: std::path::PathBuf,
 
         #[arg(short, long)]
         wildcard_fw: bool,
+
+        /// Only update devices whose model (as printed by `info`) is exactly this
+        #[arg(long)]
+        model: Option<String>,
+
+        /// Firmware version the file installs, as printed by `info` [default: the file's release
+        /// number]
+        #[arg(long)]
+        fw_version: Option<FirmwareVersion>,
+
+        /// Seconds to wait between scans for new devices
+        #[arg(long, default_value_t = 2)]
+        interval: u64,
+
+        /// Allow writing firmware whose hash isn't listed in the manifest file named in the
+        /// configuration file
+        #[arg(long)]
+        allow_unknown: bool,
+
+        #[command(flatten)]
+        checks: VersionChecks,
+    },
+
+    /// Update every matching device whose firmware differs from what a policy file says it should be
+    Reconcile {
+        #[command(flatten)]
+        spec: DeviceSpec,
+
+        /// TOML file listing which firmware file each device model should run
+        policy: std::path::PathBuf,
+
+        #[arg(short, long)]
+        wildcard_fw: bool,
+
+        /// Update out-of-date devices without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
+
+        /// Allow writing firmware whose hash isn't listed in the manifest file named in the
+        /// configuration file
+        #[arg(long)]
+        allow_unknown: bool,
+
+        #[command(flatten)]
+        checks: VersionChecks,
+    },
+
+    /// Find a device's latest firmware in a local copy of Bose's download server
+    Catalog {
+        #[command(flatten)]
+        spec: DeviceSpec,
+
+        /// Directory holding lookup.xml and index.xml files laid out as on Bose's server
+        mirror: std::path::PathBuf,
+
+        /// Look up the device with this DFU-mode USB product ID (as an unprefixed hex string)
+        /// instead of a connected device
+        #[arg(long, value_parser = parse_pid)]
+        dfu_pid: Option<u16>,
+    },
+
+    /// Download a device's latest firmware from Bose's download server into a local cache
+    Fetch {
+        #[command(flatten)]
+        spec: DeviceSpec,
+
+        /// Fetch firmware for the device with this DFU-mode USB product ID (as an unprefixed hex
+        /// string) instead of a connected device
+        #[arg(long, value_parser = parse_pid)]
+        dfu_pid: Option<u16>,
+
+        /// Base URL of the download server [default: download_server from the configuration file,
+        /// or https://downloads.bose.com]
+        #[arg(long)]
+        server: Option<String>,
+
+        /// Directory to cache downloaded files in, laid out as on the server so that it can be
+        /// passed to `catalog` [default: bose-dfu inside the OS's cache directory]
+        #[arg(long)]
+        cache_dir: Option<std::path::PathBuf>,
+    },
+
+    /// Index and search a local library of firmware files
+    Firmware {
+        /// Directory holding the library's firmware files [default: firmware_library from the
+        /// configuration file, or bose-dfu/firmware inside the OS's data directory]
+        #[arg(long)]
+        library: Option<std::path::PathBuf>,
+
+        #[command(subcommand)]
+        command: FirmwareCommand,
+    },
+
+    /// Check the log of operations that changed a device's state
+    Audit {
+        /// Audit log file [default: audit_log from the configuration file]
+        #[arg(long)]
+        log: Option<std::path::PathBuf>,
+
+        #[command(subcommand)]
+        command: AuditCommand,
+    },
+
+    /// Wrap a raw firmware image in a DFU suffix so that it can be written by `download`
+    Convert {
+        /// Raw firmware image, without a DFU suffix
+        input: std::path::PathBuf,
+
+        /// Where to write the DFU file
+        output: std::path::PathBuf,
+
+        /// Device the image is for, given by name (as printed by `file-info`) or by DFU-mode USB
+        /// ID
+        #[arg(long, required_unless_present = "wildcard")]
+        device: Option<String>,
+
+        /// Leave the file's USB ID unset, so that it's accepted by any device (but only if `-w` is
+        /// passed to `download`)
+        #[arg(long, conflicts_with = "device")]
+        wildcard: bool,
+
+        /// Firmware version to record as the file's release number [default: none]
+        #[arg(long)]
+        fw_version: Option<FirmwareVersion>,
     },
 
     /// Print metadata about a firmware file, no device needed
-    FileInfo { file: std::path::PathBuf },
+    FileInfo {
+        file: std::path::PathBuf,
+
+        /// Fail if the file has any of the problems listed under "Warnings"
+        #[arg(long)]
+        strict: bool,
+    },
+
+    /// Compare two firmware files, no device needed
+    FileDiff {
+        old: std::path::PathBuf,
+        new: std::path::PathBuf,
+    },
 }
 
 #[derive(Parser, Debug)]
@@ -69,6 +350,23 @@ struct DeviceSpec {
     #[arg(short, value_parser = parse_pid)]
     pid: Option<u16>,
 
+    /// Nickname given to the device's USB serial number in the configuration file
+    #[arg(short, long, conflicts_with = "serial")]
+    name: Option<String>,
+
+    /// Hardware serial number reported by the device's firmware (can't be used to select devices
+    /// in DFU mode)
+    #[arg(long)]
+    hw_serial: Option<String>,
+
+    /// Case-insensitive substring of the USB product string
+    #[arg(long)]
+    product: Option<String>,
+
+    /// OS-specific device path, as printed by `list --verbose`
+    #[arg(long)]
+    path: Option<String>,
+
     /// Proceed with operation even if device is untested or might be in wrong mode
     #[arg(short, long)]
     force: bool,
@@ -78,10 +376,85 @@ struct DeviceSpec {
     required_mode: Option<DeviceMode>,
 }
 
+#[derive(clap::Subcommand, Debug)]
+enum FirmwareCommand {
+    /// Scan the library for firmware files, save an index of them, and list them
+    List,
+
+    /// List the firmware files in the library that a device would accept, newest first
+    Find {
+        #[command(flatten)]
+        spec: DeviceSpec,
+
+        /// Find firmware for the device with this DFU-mode USB product ID (as an unprefixed hex
+        /// string) instead of a connected device
+        #[arg(long, value_parser = parse_pid)]
+        dfu_pid: Option<u16>,
+    },
+
+    /// Check that no file in the library has changed since it was last indexed by `list`
+    Verify,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum AuditCommand {
+    /// Check that no entry in the audit log has been modified, removed, or reordered
+    Verify,
+}
+
+/// Flags that allow writing firmware that isn't newer than what a device is running.
+#[derive(clap::Args, Clone, Copy, Debug)]
+struct VersionChecks {
+    /// Allow installing firmware older than what the device is running
+    #[arg(long)]
+    allow_downgrade: bool,
+
+    /// Allow installing the same firmware version the device is already running
+    #[arg(long)]
+    reinstall: bool,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum InventoryFormat {
+    Csv,
+    Json,
+}
+
 fn parse_pid(src: &str) -> Result<u16, std::num::ParseIntError> {
     u16::from_str_radix(src, 16)
 }
 
+impl Opt {
+    /// Whether the subcommand depends on the configuration file for more than cosmetic details
+    /// like nicknames. Other subcommands still run if the file can't be loaded.
+    fn needs_config(&self) -> bool {
+        !matches!(
+            self,
+            Opt::List { .. }
+                | Opt::Inventory { .. }
+                | Opt::Convert { .. }
+                | Opt::FileInfo { .. }
+                | Opt::FileDiff { .. }
+        )
+    }
+
+    /// Whether the subcommand can change a device's state, and so must be recorded in the audit
+    /// log.
+    fn changes_devices(&self) -> bool {
+        matches!(
+            self,
+            Opt::Tap { .. }
+                | Opt::EnterDfu { .. }
+                | Opt::LeaveDfu { .. }
+                | Opt::Download { .. }
+                | Opt::Update { .. }
+                | Opt::Rollback { .. }
+                | Opt::Watch { .. }
+                | Opt::Reconcile { .. }
+        )
+    }
+}
+
 fn main() -> Result<()> {
     env_logger::Builder::from_env(
         env_logger::Env::new()
@@ -93,16 +466,30 @@ fn main() -> Result<()> {
 
     let mode = Opt::parse();
 
-    let api = HidApi::new()?;
+    let mut api = HidApi::new()?;
+    let (config, config_loaded) = match Config::load_default() {
+        Ok(config) => (config, true),
+        Err(e) if !mode.needs_config() => {
+            warn!("Using default settings; {:#}", anyhow!(e));
+            (Config::default(), false)
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let auditor = Auditor::new(&config);
+    // Never refuse leave-dfu, since it's how a device stuck in DFU mode is recovered.
+    if mode.changes_devices() && !matches!(mode, Opt::LeaveDfu { .. }) {
+        auditor.check()?;
+    }
 
     match mode {
-        Opt::List => list_cmd(&api),
+        Opt::List { verbose } => list_cmd(&api, &config, verbose),
+        Opt::Inventory { format, force } => inventory_cmd(&api, &config, format, force)?,
         Opt::Info { spec } => {
             let spec = DeviceSpec {
                 required_mode: Some(DeviceMode::Normal),
                 ..spec
             };
-            let (dev, info) = spec.get_device(&api)?;
+            let (dev, info) = spec.get_device(&api, &config)?;
 
             use bose_dfu::protocol::InfoField::*;
             println!("USB serial: {}", info.serial_number().unwrap_or("INVALID"));
@@ -118,14 +505,23 @@ fn main() -> Result<()> {
                 required_mode: Some(DeviceMode::Normal),
                 ..spec
             };
-            tap_command_loop(&spec.get_device(&api)?.0)?;
+            let (dev, info) = spec.get_device(&api, &config)?;
+            let mut commands = vec![];
+            let result = tap_command_loop(&dev, &mut commands);
+
+            let mut entry = auditor.entry("tap", vec![audit_device(info)], &result);
+            entry.tap_commands = commands;
+            auditor.record(entry, result)?;
         }
         Opt::EnterDfu { spec } => {
             let spec = DeviceSpec {
                 required_mode: Some(DeviceMode::Normal),
                 ..spec
             };
-            enter_dfu(&spec.get_device(&api)?.0)?;
+            let (dev, info) = spec.get_device(&api, &config)?;
+            let result = enter_dfu(&dev).map_err(anyhow::Error::from);
+            let entry = auditor.entry("enter-dfu", vec![audit_device(info)], &result);
+            auditor.record(entry, result)?;
             info!("Note that device may take a few seconds to change mode");
         }
         Opt::LeaveDfu { spec } => {
@@ -133,136 +529,2420 @@ fn main() -> Result<()> {
                 required_mode: Some(DeviceMode::Dfu),
                 ..spec
             };
-            let (dev, _) = spec.get_device(&api)?;
-            ensure_idle(&dev)?;
-            leave_dfu(&dev)?;
+            let (dev, info) = spec.get_device(&api, &config)?;
+            let result = ensure_idle(&dev)
+                .and_then(|()| leave_dfu(&dev))
+                .map_err(anyhow::Error::from);
+            let entry = auditor.entry("leave-dfu", vec![audit_device(info)], &result);
+            // Recovering the device matters more than recording it, so only warn.
+            if let Err(e) = auditor.append(entry) {
+                warn!("Failed to record operation in audit log: {e:#}");
+            }
+            result?;
         }
         Opt::Download {
             spec,
             file,
+            release,
             wildcard_fw,
+            all,
+            verify,
+            backup,
+            raw,
+            allow_unknown,
         } => {
             let spec = DeviceSpec {
                 required_mode: Some(DeviceMode::Dfu),
                 ..spec
             };
-            let (dev, info) = spec.get_device(&api)?;
-            download_cmd(&dev, info, &file, wildcard_fw)?
+            let raw = raw.as_deref().map(device_by_name).transpose()?;
+            let options = DownloadOptions {
+                wildcard_fw,
+                verify,
+                backup: backup_store(&config, backup)?,
+                history: history_path(&config),
+            };
+            if all {
+                let devices = spec.get_devices(&api, &config)?;
+                // Every device must accept the same file, so looking it up for one is enough.
+                let target = FirmwareTarget {
+                    dfu_id: usb_id(devices[0].1),
+                    model: None,
+                };
+                let mut hash = None;
+                let prepare = || -> Result<_> {
+                    let path = choose_firmware(&config, file, release, target.dfu_id)?;
+                    let firmware = load_firmware(&path, raw, &target)?;
+                    hash = Some(firmware.sha256.clone());
+                    check_manifest(&config, &firmware, allow_unknown)?;
+                    Ok((path, firmware))
+                };
+                match prepare() {
+                    Ok((path, firmware)) => {
+                        batch_download_cmd(devices, &path, firmware, &options, &auditor)?
+                    }
+                    Err(e) => {
+                        // Nothing was written, but record the attempt against every device.
+                        let result = Err(e);
+                        let audit_devices = devices.iter().map(|(_, i)| audit_device(i)).collect();
+                        let mut entry = auditor.entry("download", audit_devices, &result);
+                        entry.file_sha256 = hash;
+                        auditor.record(entry, result)?;
+                    }
+                }
+            } else {
+                let (dev, info) = spec.get_device(&api, &config)?;
+                let target = FirmwareTarget {
+                    dfu_id: usb_id(info),
+                    model: None,
+                };
+                let mut hash = None;
+                let download = || -> Result<()> {
+                    let path = choose_firmware(&config, file, release, target.dfu_id)?;
+                    let firmware = load_firmware(&path, raw, &target)?;
+                    hash = Some(firmware.sha256.clone());
+                    check_manifest(&config, &firmware, allow_unknown)?;
+                    download_cmd(&dev, info, &path, firmware, &options)
+                };
+                let result = download();
+
+                let mut entry = auditor.entry("download", vec![audit_device(info)], &result);
+                entry.file_sha256 = hash;
+                auditor.record(entry, result)?;
+            }
+        }
+        Opt::UploadDiff { spec, file, save } => {
+            let spec = DeviceSpec {
+                required_mode: Some(DeviceMode::Dfu),
+                ..spec
+            };
+            let (dev, info) = spec.get_device(&api, &config)?;
+            upload_diff_cmd(&dev, info, &file, save.as_deref())?;
         }
-        Opt::FileInfo { file: path } => {
-            let mut file = std::fs::File::open(path)?;
-            let suffix = parse_dfu_file(&mut file)?;
+        Opt::Update {
+            spec,
+            file,
+            release,
+            wildcard_fw,
+            fw_version,
+            allow_unknown,
+            backup,
+            checks,
+        } => {
+            let spec = DeviceSpec {
+                required_mode: Some(DeviceMode::Normal),
+                ..spec
+            };
+            let backup_store = backup_store(&config, backup)?;
+            let (dev, info) = spec.get_device(&api, &config)?;
+            let target = UpdateTarget::new(info);
 
-            println!(
-                "For USB ID: {:04x}:{:04x}",
-                suffix.vendor_id, suffix.product_id
+            let mut hash = None;
+            let update = || -> Result<()> {
+                let path = choose_firmware(&config, file, release, target.id)?;
+                let model = read_info_field(&dev, InfoField::DeviceModel)?;
+                let fw_target = FirmwareTarget {
+                    dfu_id: dfu_mode_id(target.id),
+                    model: Some(&model),
+                };
+                let mut firmware = load_firmware(&path, None, &fw_target)?;
+                hash = Some(firmware.sha256.clone());
+                check_manifest(&config, &firmware, allow_unknown)?;
+
+                let current = read_info_field(&dev, InfoField::CurrentFirmware)?;
+                match installed_version(&firmware.file, fw_version.as_ref()) {
+                    Ok(new) => checks.check(&current, &new)?,
+                    Err(e) if checks.allow_downgrade => {
+                        warn!("{e:#}; not checking for downgrade")
+                    }
+                    Err(e) => {
+                        return Err(e.context("pass --allow-downgrade to skip the downgrade check"));
+                    }
+                }
+
+                let backup = backup_store.as_ref().map(|store| Backup {
+                    store,
+                    version: Some(&current),
+                });
+                update_device(
+                    &mut api,
+                    dev,
+                    &target,
+                    &mut firmware.file,
+                    wildcard_fw,
+                    backup,
+                )?;
+                record_update(
+                    history_path(&config).as_deref(),
+                    &target,
+                    Some(&current),
+                    &path,
+                    &firmware,
+                    fw_version.as_ref(),
+                    None,
+                );
+                Ok(())
+            };
+            let result = update();
+
+            let mut entry = auditor.entry("update", vec![target.audit_device()], &result);
+            entry.file_sha256 = hash;
+            auditor.record(entry, result)?;
+        }
+        Opt::Rollback {
+            spec,
+            wildcard_fw,
+            allow_unknown,
+        } => {
+            let spec = DeviceSpec {
+                required_mode: Some(DeviceMode::Normal),
+                ..spec
+            };
+            let (dev, info) = spec.get_device(&api, &config)?;
+            let target = UpdateTarget::new(info);
+            let mut hash = None;
+            let result = rollback_cmd(
+                &mut api,
+                &config,
+                dev,
+                &target,
+                wildcard_fw,
+                allow_unknown,
+                &mut hash,
             );
-            match suffix.has_valid_crc() {
-                true => println!("CRC: valid ({:#010x})", suffix.expected_crc),
-                false => println!(
-                    "CRC: INVALID (expected {:#010x}, actual {:#010x})",
-                    suffix.expected_crc, suffix.actual_crc
-                ),
+
+            let mut entry = auditor.entry("rollback", vec![target.audit_device()], &result);
+            entry.file_sha256 = hash;
+            auditor.record(entry, result)?;
+        }
+        Opt::Watch {
+            spec,
+            file,
+            wildcard_fw,
+            model,
+            fw_version,
+            interval,
+            allow_unknown,
+            checks,
+        } => {
+            let spec = DeviceSpec {
+                required_mode: Some(DeviceMode::Normal),
+                ..spec
+            };
+            let options = WatchOptions {
+                file,
+                wildcard_fw,
+                model,
+                fw_version,
+                interval: Duration::from_secs(interval),
+                allow_unknown,
+                checks,
+            };
+            watch_cmd(&mut api, &config, &spec, &options, &auditor)?;
+        }
+        Opt::Reconcile {
+            spec,
+            policy,
+            wildcard_fw,
+            yes,
+            allow_unknown,
+            checks,
+        } => {
+            let spec = DeviceSpec {
+                required_mode: Some(DeviceMode::Normal),
+                ..spec
+            };
+            let options = ReconcileOptions {
+                wildcard_fw,
+                yes,
+                allow_unknown,
+                checks,
+            };
+            reconcile_cmd(&mut api, &config, &spec, &policy, &options, &auditor)?;
+        }
+        Opt::Catalog {
+            spec,
+            mirror,
+            dfu_pid,
+        } => {
+            let id = match dfu_pid {
+                Some(pid) => UsbId { vid: BOSE_VID, pid },
+                None => usb_id(spec.get_device(&api, &config)?.1),
+            };
+            catalog_cmd(&mirror, id)?;
+        }
+        Opt::Fetch {
+            spec,
+            dfu_pid,
+            server,
+            cache_dir,
+        } => {
+            let id = match dfu_pid {
+                Some(pid) => UsbId { vid: BOSE_VID, pid },
+                None => usb_id(spec.get_device(&api, &config)?.1),
+            };
+            let server = server
+                .or(config.download_server.clone())
+                .unwrap_or_else(|| DEFAULT_SERVER.to_owned());
+            let Some(cache_dir) = cache_dir.or_else(Fetcher::default_cache_dir) else {
+                bail!("can't find a cache directory; use --cache-dir to choose one");
+            };
+            fetch_cmd(&Fetcher::new(&server, &cache_dir), id)?;
+        }
+        Opt::Firmware { library, command } => {
+            let Some(dir) = library
+                .or(config.firmware_library.clone())
+                .or_else(Library::default_dir)
+            else {
+                bail!("can't find a firmware library directory; use --library to choose one");
+            };
+            match command {
+                FirmwareCommand::List => firmware_list_cmd(&dir)?,
+                FirmwareCommand::Find { spec, dfu_pid } => {
+                    let id = match dfu_pid {
+                        Some(pid) => UsbId { vid: BOSE_VID, pid },
+                        None => usb_id(spec.get_device(&api, &config)?.1),
+                    };
+                    firmware_find_cmd(&dir, id)?;
+                }
+                FirmwareCommand::Verify => firmware_verify_cmd(&dir)?,
+            }
+        }
+        Opt::Audit { log, command } => {
+            let Some(path) = log.or(config.audit_log.clone()) else {
+                bail!(
+                    "no audit log configured; use --log or set audit_log in the configuration file"
+                );
+            };
+            match command {
+                AuditCommand::Verify => audit_verify_cmd(&AuditLog::new(&path))?,
             }
         }
+        Opt::Convert {
+            input,
+            output,
+            device,
+            // Clap requires exactly one of --device and --wildcard, so this is implied by device
+            // being None.
+            wildcard: _,
+            fw_version,
+        } => {
+            let device = device.as_deref().map(device_by_name).transpose()?;
+            convert_cmd(&input, &output, device, fw_version.as_ref())?;
+        }
+        Opt::FileInfo { file: path, strict } => {
+            let validation = match strict {
+                true => Validation::Strict,
+                false => Validation::Lenient,
+            };
+            file_info_cmd(config_loaded.then_some(&config), &path, validation)?
+        }
+        Opt::FileDiff { old, new } => file_diff_cmd(&old, &new)?,
     };
 
     Ok(())
 }
 
-fn list_cmd(hidapi: &HidApi) {
+fn list_cmd(hidapi: &HidApi, config: &Config, verbose: bool) {
     for dev in hidapi.device_list() {
         let dev_id = UsbId {
             vid: dev.vendor_id(),
             pid: dev.product_id(),
         };
 
-        let state = identify_device(dev_id, dev.usage_page());
-        if let DeviceCompat::Incompatible = state {
+        if let DeviceCompat::Incompatible = identify_device(dev_id, dev.usage_page()) {
             continue;
         }
 
-        println!(
-            "{} {} {} [{}]",
-            dev_id,
-            dev.serial_number().unwrap_or("INVALID"),
-            dev.product_string().unwrap_or("INVALID"),
-            state,
-        );
+        let mut line = summarize_device(config, dev);
+        if verbose {
+            let _ = write!(line, " path: {}", dev.path().to_string_lossy());
+        }
+        println!("{line}");
     }
 }
 
-fn tap_command_loop(device: &HidDevice) -> Result<()> {
-    let mut rl = DefaultEditor::new()?;
+/// One device's row in the output of the `inventory` subcommand.
+#[derive(Serialize, Debug, Default)]
+struct InventoryRow {
+    usb_id: String,
+    usb_serial: Option<String>,
+    nickname: Option<String>,
+    product: Option<String>,
+    compatibility: String,
+    mode: String,
+    hw_serial: Option<String>,
+    model: Option<String>,
+    firmware: Option<String>,
+    dfu_state: Option<String>,
+    dfu_status: Option<String>,
+    error: Option<String>,
+}
 
-    loop {
-        let readline = rl.readline("> ");
-        match readline {
-            Ok(line) => {
-                if line.is_empty() {
-                    continue;
-                } else if line == "." {
-                    break;
-                }
-                rl.add_history_entry(line.as_str())?;
+fn inventory_cmd(
+    hidapi: &HidApi,
+    config: &Config,
+    format: InventoryFormat,
+    force: bool,
+) -> Result<()> {
+    let mut rows = vec![];
+    for dev in hidapi.device_list() {
+        let dev_id = usb_id(dev);
+        let (untested, mode) = match identify_device(dev_id, dev.usage_page()) {
+            DeviceCompat::Compatible(mode) => (false, mode),
+            DeviceCompat::Untested(mode) => (true, mode),
+            DeviceCompat::Incompatible => continue,
+        };
 
-                let result = run_tap_command(device, line.as_bytes());
-                println!("{result:?}");
-            }
-            Err(ReadlineError::Interrupted) => {
-                println!("CTRL-C");
-                break;
-            }
-            Err(ReadlineError::Eof) => {
-                println!("CTRL-D");
-                break;
-            }
-            Err(err) => {
-                println!("Error: {err:?}");
-                break;
+        let mut row = InventoryRow {
+            usb_id: dev_id.to_string(),
+            usb_serial: dev.serial_number().map(str::to_owned),
+            nickname: dev
+                .serial_number()
+                .and_then(|s| config.nickname_for(s))
+                .map(str::to_owned),
+            product: dev.product_string().map(str::to_owned),
+            compatibility: if untested { "untested" } else { "compatible" }.to_owned(),
+            mode: mode.to_string(),
+            ..Default::default()
+        };
+
+        if untested && !force {
+            row.error = Some("untested device not queried; pass -f to query it".to_owned());
+        } else if let Err(e) = query_inventory(hidapi, dev, mode, force, &mut row) {
+            row.error = Some(format!("{e:#}"));
+        }
+
+        rows.push(row);
+    }
+
+    match format {
+        InventoryFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(std::io::stdout());
+            for row in rows {
+                writer.serialize(row)?;
             }
+            writer.flush()?;
+        }
+        InventoryFormat::Json => {
+            serde_json::to_writer_pretty(std::io::stdout(), &rows)?;
+            println!();
         }
     }
 
     Ok(())
 }
 
-fn download_cmd(dev: &HidDevice, info: &DeviceInfo, path: &Path, wildcard_fw: bool) -> Result<()> {
-    let mut file = std::fs::File::open(path)?;
-    let suffix = parse_dfu_file(&mut file)?;
-    suffix.ensure_valid_crc()?;
+/// Fill in the parts of an inventory row that require talking to the device. Devices in normal
+/// mode are queried over TAP; devices in DFU mode are asked for their status instead. Devices in an
+/// unknown mode, which includes every untested device, are only queried over TAP if `force` is set.
+fn query_inventory(
+    hidapi: &HidApi,
+    dev: &DeviceInfo,
+    mode: DeviceMode,
+    force: bool,
+    row: &mut InventoryRow,
+) -> Result<()> {
+    if mode == DeviceMode::Unknown && !force {
+        bail!("device mode unknown; pass -f to query it over TAP");
+    }
 
-    let dev_id = UsbId {
-        vid: info.vendor_id(),
-        pid: info.product_id(),
-    };
+    let open = dev
+        .open_device(hidapi)
+        .context("failed to open device; do you have permission?")?;
 
-    if !suffix.vendor_id.matches(dev_id.vid) || !suffix.product_id.matches(dev_id.pid) {
-        bail!(
-            "this file is not for the selected device: file for {:04x}:{:04x}, device is {}",
-            suffix.vendor_id,
-            suffix.product_id,
-            dev_id
-        );
+    if mode == DeviceMode::Dfu {
+        let status = read_status(&open)?;
+        row.dfu_state = Some(format!("{:?}", status.state));
+        row.dfu_status = Some(format!("{:?}", status.status));
+    } else {
+        use bose_dfu::protocol::InfoField::*;
+        row.hw_serial = Some(read_info_field(&open, SerialNumber)?);
+        row.model = Some(read_info_field(&open, DeviceModel)?);
+        row.firmware = Some(read_info_field(&open, CurrentFirmware)?);
     }
 
+    Ok(())
+}
+
+fn catalog_cmd(mirror: &Path, id: UsbId) -> Result<()> {
+    let dfu_id = find_device_ids(id).map_or(id, |ids| ids.dfu_mode);
+
+    let lookup_path = mirror.join("lookup.xml");
+    let lookup = std::fs::read_to_string(&lookup_path)
+        .map_err(anyhow::Error::from)
+        .and_then(|xml| Ok(Lookup::parse(&xml)?))
+        .with_context(|| format!("failed to load {}", lookup_path.display()))?;
+    let Some(product) = lookup.find(id) else {
+        bail!("lookup.xml has no entry for DFU-mode USB ID {dfu_id}");
+    };
+
+    println!("DFU-mode USB ID: {dfu_id}");
+    println!("Codename: {}", product.codename().unwrap_or("UNKNOWN"));
+    println!("Index: {}", product.index_url);
+
+    let index_path = mirror.join(product.index_path());
+    let index = std::fs::read_to_string(&index_path)
+        .map_err(anyhow::Error::from)
+        .and_then(|xml| Ok(Index::parse(&xml)?))
+        .with_context(|| format!("failed to load {}", index_path.display()))?;
+    let Some(image) = index.latest() else {
+        bail!("{} lists no firmware images", index_path.display());
+    };
+
+    match image.revision {
+        Some(ref revision) => println!("Latest image: {} (revision {revision})", image.filename),
+        None => println!("Latest image: {}", image.filename),
+    }
+
+    let local = mirror.join(product.directory()).join(&image.filename);
+    if !local.exists() {
+        println!("Local file: none (expected at {})", local.display());
+        return Ok(());
+    }
+
+    let check = || -> Result<()> {
+        let file = DfuFile::open(&local)?;
+        file.ensure_valid_crc()?;
+        if !file.matches_device(dfu_id) {
+            let suffix = file.suffix();
+            bail!(
+                "file is for {:04x}:{:04x}",
+                suffix.vendor_id,
+                suffix.product_id
+            );
+        }
+        Ok(())
+    };
+    match check() {
+        Ok(_) => println!("Local file: {} (valid)", local.display()),
+        Err(e) => println!("Local file: {} (INVALID: {e:#})", local.display()),
+    }
+
+    Ok(())
+}
+
+fn fetch_cmd(fetcher: &Fetcher, id: UsbId) -> Result<()> {
+    let dfu_id = find_device_ids(id).map_or(id, |ids| ids.dfu_mode);
+
+    let lookup = fetcher.lookup()?;
+    let Some(product) = lookup.find(id) else {
+        bail!("lookup.xml has no entry for DFU-mode USB ID {dfu_id}");
+    };
+    info!(
+        "Device codename is {}",
+        product.codename().unwrap_or("UNKNOWN")
+    );
+
+    let index = fetcher.index(product)?;
+    if index.images.is_empty() {
+        bail!("{} lists no firmware images", product.index_url);
+    }
+
+    for image in &index.images {
+        let (path, downloaded) = fetcher.image(product, image, dfu_id)?;
+        let revision = match image.revision {
+            Some(ref revision) => format!(" (revision {revision})"),
+            None => String::new(),
+        };
+        match downloaded {
+            true => println!("Downloaded {}{revision}", path.display()),
+            false => println!("Already have {}{revision}", path.display()),
+        }
+    }
+
+    Ok(())
+}
+
+fn firmware_list_cmd(dir: &Path) -> Result<()> {
+    let (library, rejected) = Library::scan(dir)?;
+    for (path, e) in rejected {
+        warn!(
+            "Skipping {}: {:#}",
+            path.display(),
+            anyhow::Error::from(e).root_cause()
+        );
+    }
+    library.save()?;
+
+    for entry in &library.entries {
+        println!("{}", describe_library_entry(entry));
+    }
+    Ok(())
+}
+
+fn firmware_find_cmd(dir: &Path, id: UsbId) -> Result<()> {
+    let dfu_id = find_device_ids(id).map_or(id, |ids| ids.dfu_mode);
+
+    let library = Library::open(dir)?;
+    let found = library.find(dfu_id);
+    if found.is_empty() {
+        bail!(
+            "no firmware in {} for DFU-mode USB ID {dfu_id}; \
+            run `firmware list` if files were added since it was indexed",
+            dir.display()
+        );
+    }
+
+    for entry in found {
+        println!("{}", describe_library_entry(entry));
+    }
+    Ok(())
+}
+
+fn firmware_verify_cmd(dir: &Path) -> Result<()> {
+    let library = Library::load(dir).context("run `firmware list` to create the index")?;
+
+    let mut failed = 0;
+    for entry in &library.entries {
+        let path = entry.path.display();
+        match library.verify(entry) {
+            EntryStatus::Ok => {
+                println!("OK: {path}");
+                continue;
+            }
+            EntryStatus::Missing => println!("MISSING: {path}"),
+            EntryStatus::Modified => println!("MODIFIED: {path}"),
+            EntryStatus::Unreadable(e) => {
+                println!("UNREADABLE: {path} ({:#})", anyhow::Error::from(e))
+            }
+        }
+        failed += 1;
+    }
+
+    if failed > 0 {
+        bail!(
+            "{failed} of {} files failed verification",
+            library.entries.len()
+        );
+    }
+    Ok(())
+}
+
+fn describe_library_entry(entry: &FirmwareEntry) -> String {
+    let version = match entry.firmware_version() {
+        Some(v) => v.to_string(),
+        None => format!("unknown (release {:04x})", entry.release_number),
+    };
+    format!(
+        "{}: for {:04x}:{:04x}, version {version}, {} bytes, CRC {:#010x}, SHA-256 {}",
+        entry.path.display(),
+        entry.vendor_id,
+        entry.product_id,
+        entry.size,
+        entry.crc,
+        entry.sha256
+    )
+}
+
+/// Pick the firmware file to write to a device with the given USB ID: either `file` or, if a
+/// release was requested instead, the file with that version in the firmware library.
+fn choose_firmware(
+    config: &Config,
+    file: Option<std::path::PathBuf>,
+    release: Option<FirmwareVersion>,
+    id: UsbId,
+) -> Result<std::path::PathBuf> {
+    let Some(release) = release else {
+        return file.ok_or_else(|| anyhow!("no firmware file given"));
+    };
+
+    let Some(dir) = config
+        .firmware_library
+        .clone()
+        .or_else(Library::default_dir)
+    else {
+        bail!("can't find a firmware library directory");
+    };
+    let dfu_id = dfu_mode_id(id);
+
+    let library = Library::open(&dir)?;
+    let Some(entry) = library.find_version(dfu_id, &release) else {
+        bail!(
+            "no firmware with version {release} for DFU-mode USB ID {dfu_id} in {}; \
+            run `firmware list` if files were added since it was indexed",
+            dir.display()
+        );
+    };
+
+    let path = library.path_of(entry);
+    match library.verify(entry) {
+        EntryStatus::Ok => (),
+        EntryStatus::Missing => bail!(
+            "{} is in the firmware index but missing; run `firmware list` to update the index",
+            path.display()
+        ),
+        EntryStatus::Modified => bail!(
+            "{} has changed since it was indexed; run `firmware list` to update the index",
+            path.display()
+        ),
+        EntryStatus::Unreadable(e) => {
+            return Err(
+                anyhow::Error::from(e).context(format!("failed to read {}", path.display()))
+            );
+        }
+    }
+    info!("Using firmware file {}", path.display());
+    Ok(path)
+}
+
+/// Run TAP commands typed by the user until they quit, adding each command sent to `sent`.
+fn tap_command_loop(device: &HidDevice, sent: &mut Vec<String>) -> Result<()> {
+    let mut rl = DefaultEditor::new()?;
+
+    loop {
+        let readline = rl.readline("> ");
+        match readline {
+            Ok(line) => {
+                if line.is_empty() {
+                    continue;
+                } else if line == "." {
+                    break;
+                }
+                rl.add_history_entry(line.as_str())?;
+
+                sent.push(line.clone());
+                let result = run_tap_command(device, line.as_bytes());
+                println!("{result:?}");
+            }
+            Err(ReadlineError::Interrupted) => {
+                println!("CTRL-C");
+                break;
+            }
+            Err(ReadlineError::Eof) => {
+                println!("CTRL-D");
+                break;
+            }
+            Err(err) => {
+                println!("Error: {err:?}");
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Options for the `download` subcommand.
+struct DownloadOptions {
+    wildcard_fw: bool,
+    verify: bool,
+    backup: Option<BackupStore>,
+    history: Option<std::path::PathBuf>,
+}
+
+fn download_cmd(
+    dev: &HidDevice,
+    info: &DeviceInfo,
+    path: &Path,
+    mut firmware: LoadedFirmware,
+    options: &DownloadOptions,
+) -> Result<()> {
+    check_firmware(&firmware.file, usb_id(info), options.wildcard_fw)?;
+    if let Some(ref store) = options.backup {
+        back_up_firmware(dev, store, info.serial_number(), usb_id(info), None)?;
+    }
+
+    info!("Can't read firmware version in DFU mode, so not checking for downgrade");
+    write_firmware(dev, &mut firmware.file)?;
+
+    let verification = match options.verify {
+        true => {
+            let serial = info.serial_number().unwrap_or("INVALID");
+            let payload = firmware.file.payload_bytes()?;
+            Some(verify_download(dev, serial, usb_id(info), &payload)?)
+        }
+        false => None,
+    };
+    // Only once verified, so the history never claims firmware that failed to verify.
+    record_update(
+        options.history.as_deref(),
+        &UpdateTarget::new(info),
+        None,
+        path,
+        &firmware,
+        None,
+        verification,
+    );
+    Ok(())
+}
+
+/// Where to save backups, if the user asked for them: the directory named in the configuration
+/// file, or the default one.
+fn backup_store(config: &Config, backup: bool) -> Result<Option<BackupStore>> {
+    if !backup {
+        return Ok(None);
+    }
+
+    let Some(dir) = config.backup_dir.clone().or_else(BackupStore::default_dir) else {
+        bail!("can't find a backup directory; set backup_dir in the configuration file");
+    };
+    Ok(Some(BackupStore::new(&dir)))
+}
+
+/// A backup to take in [update_device] once the device is in DFU mode.
+struct Backup<'a> {
+    store: &'a BackupStore,
+    /// Firmware version the device reported before entering DFU mode.
+    version: Option<&'a str>,
+}
+
+/// Read the firmware from a device in DFU mode and save it, along with the device's details, in
+/// `store`.
+fn back_up_firmware(
+    dev: &HidDevice,
+    store: &BackupStore,
+    serial: Option<&str>,
+    id: UsbId,
+    version: Option<&str>,
+) -> Result<()> {
+    let name = serial.unwrap_or("INVALID");
+    ensure_idle(dev)?;
+    info!("{name}: reading current firmware to back it up; it may take several minutes");
+
+    let mut image = vec![];
+    upload(dev, &mut image).context("failed to read firmware for backup")?;
+    let path = store.save(&image, &BackupInfo::new(&image, serial, id, version))?;
+
+    info!("{name}: saved backup to {}", path.display());
+    Ok(())
+}
+
+/// Read back the firmware on a device in DFU mode and check it against the payload just written
+/// to it, using the device's [ReadbackRule]. Devices without one can't be verified, so they're
+/// reported as [Verification::Unverified] rather than failing.
+fn verify_download(
+    dev: &HidDevice,
+    serial: &str,
+    id: UsbId,
+    payload: &[u8],
+) -> Result<Verification> {
+    let Some(rule) = find_device_ids(id).and_then(|ids| ids.readback) else {
+        warn!("{serial}: unverified; it's not known what this device reads back");
+        return Ok(Verification::Unverified);
+    };
+
+    ensure_idle(dev)?;
+    info!("{serial}: reading firmware back to verify it");
+    let mut read_back = vec![];
+    upload(dev, &mut read_back)?;
+    check_readback(rule, payload, &read_back)?;
+
+    info!("{serial}: verified");
+    Ok(Verification::Verified)
+}
+
+/// Check that `read_back` holds `payload`, according to `rule`.
+fn check_readback(rule: ReadbackRule, payload: &[u8], read_back: &[u8]) -> Result<()> {
+    let Some(normalized) = rule.normalize(read_back, payload.len()) else {
+        bail!(
+            "verification failed: device read back only {} bytes",
+            read_back.len()
+        );
+    };
+    if normalized != payload {
+        let diff = BlockDiff::compare(payload, normalized, XFER_DATA_SIZE);
+        bail!(
+            "verification failed: {} of {} blocks read back differently",
+            diff.changed_blocks(),
+            diff.total_blocks
+        );
+    }
+    Ok(())
+}
+
+/// Print what's known about the firmware file at `path`. `config` is [None] if the configuration
+/// file couldn't be loaded, in which case the file can't be checked against its manifest.
+fn file_info_cmd(config: Option<&Config>, path: &Path, validation: Validation) -> Result<()> {
+    // Shortest run of printable characters to consider a string when looking for versions.
+    const MIN_STRING_LEN: usize = 5;
+
+    let data = match read_firmware_contents(path)? {
+        Contents::Single(data) => data,
+        Contents::Bundle(bundle) => {
+            print_bundle_info(&bundle);
+            return Ok(());
+        }
+    };
+    let file = DfuFile::with_validation(Cursor::new(&data[..]), validation)?;
+    let suffix = file.suffix();
+
+    println!(
+        "For USB ID: {:04x}:{:04x}",
+        suffix.vendor_id, suffix.product_id
+    );
+    let names = match (suffix.vendor_id.0, suffix.product_id.0) {
+        (Some(vid), Some(pid)) => device_names(UsbId { vid, pid }),
+        _ => vec![],
+    };
+    match names.is_empty() {
+        true => println!("Device: UNKNOWN"),
+        false => println!("Device: {}", names.join(", ")),
+    }
+    match suffix.firmware_version() {
+        Some(version) => println!(
+            "Release number: {:04x} (version {version})",
+            suffix.release_number
+        ),
+        None => println!("Release number: {:04x}", suffix.release_number),
+    }
+    println!("DFU version: {:#06x}", suffix.dfu_version);
+    println!("Suffix length: {} bytes", suffix.suffix_length);
+
+    match suffix.decode_extension() {
+        None => println!("Extra suffix bytes: none"),
+        Some(SuffixExtension::Text(text)) => println!("Extra suffix bytes: text {text:?}"),
+        Some(SuffixExtension::Unknown(bytes)) => {
+            println!("Extra suffix bytes: {}", hex_bytes(bytes))
+        }
+    }
+
+    match suffix.has_valid_crc() {
+        true => println!("CRC: valid ({:#010x})", suffix.expected_crc),
+        false => println!(
+            "CRC: INVALID (expected {:#010x}, actual {:#010x})",
+            suffix.expected_crc, suffix.actual_crc
+        ),
+    }
+    println!("Payload size: {} bytes", suffix.payload_length);
+    println!("SHA-256: {}", sha256_hex(&data));
+
+    if suffix.is_dfuse() {
+        print_dfuse_info(file.payload());
+    }
+
+    match suffix.warnings.is_empty() {
+        true => println!("Warnings: none"),
+        false => {
+            println!("Warnings:");
+            for warning in &suffix.warnings {
+                println!("  {warning}");
+            }
+        }
+    }
+
+    let versions: Vec<_> = printable_strings(file.payload(), MIN_STRING_LEN)
+        .into_iter()
+        .filter(|(_, s)| looks_like_version(s))
+        .collect();
+    match versions.is_empty() {
+        true => println!("Version strings: none"),
+        false => {
+            println!("Version strings:");
+            for (offset, string) in versions {
+                println!("  {offset:#010x}: {string}");
+            }
+        }
+    }
+
+    let manifest = config.map(load_manifest).transpose()?.flatten();
+    let verdict = manifest
+        .as_ref()
+        .map(|m| m.check(&sha256_hex(&data), suffix));
+    // Why download would still refuse a file the device accepts, if it would.
+    let manifest_note = match verdict {
+        Some(Verdict::Unknown) => ", but only with --allow-unknown (not in manifest)",
+        Some(Verdict::Mismatch(_)) => ", but blocked by manifest (hash mismatch)",
+        None if config.is_none() => " (manifest not checked)",
+        Some(Verdict::Known(_)) | None => "",
+    };
+
+    println!("Accepted by download for:");
+    for device in COMPATIBLE_DEVICES {
+        let id = device.dfu_mode;
+        // Use the same checks as download, so this can't disagree with it.
+        let acceptance = if validate_firmware(&file, id, false).is_ok() {
+            format!("yes{manifest_note}")
+        } else if validate_firmware(&file, id, true).is_ok() {
+            format!("only with -w{manifest_note}")
+        } else if !suffix.has_valid_crc() {
+            "no (invalid CRC)".to_owned()
+        } else if suffix.is_dfuse() {
+            "no (DfuSe file)".to_owned()
+        } else {
+            "no".to_owned()
+        };
+        println!("  {} ({id}): {acceptance}", device.name);
+    }
+
+    match verdict {
+        Some(Verdict::Known(image)) => println!(
+            "Manifest: known official image ({} {})",
+            image.model, image.version
+        ),
+        Some(Verdict::Unknown) => println!("Manifest: unknown image"),
+        Some(Verdict::Mismatch(image)) => println!(
+            "Manifest: HASH MISMATCH (expected {} for {} {})",
+            image.sha256, image.model, image.version
+        ),
+        None if config.is_none() => {
+            println!("Manifest: NOT CHECKED (configuration file couldn't be loaded)")
+        }
+        None => (),
+    }
+
+    Ok(())
+}
+
+fn file_diff_cmd(old_path: &Path, new_path: &Path) -> Result<()> {
+    // Shortest run of printable characters to consider a string. Shorter runs turn up by chance in
+    // compiled code, so they would drown out real changes.
+    const MIN_STRING_LEN: usize = 8;
+
+    if old_path == Path::new("-") && new_path == Path::new("-") {
+        bail!("only one of the files to compare can be read from standard input");
+    }
+    let old_data = read_firmware_file(old_path)?;
+    let new_data = read_firmware_file(new_path)?;
+    let old = DfuFile::from_bytes(&old_data)
+        .with_context(|| format!("failed to parse {}", old_path.display()))?;
+    let new = DfuFile::from_bytes(&new_data)
+        .with_context(|| format!("failed to parse {}", new_path.display()))?;
+    let (old_suffix, new_suffix) = (old.suffix(), new.suffix());
+
+    let print_field = |name: &str, old: String, new: String| match old == new {
+        true => println!("{name}: {old}"),
+        false => println!("{name}: {old} -> {new}"),
+    };
+    let usb_id = |s: &SuffixInfo| format!("{:04x}:{:04x}", s.vendor_id, s.product_id);
+    let release = |s: &SuffixInfo| match s.firmware_version() {
+        Some(version) => format!("{:04x} (version {version})", s.release_number),
+        None => format!("{:04x}", s.release_number),
+    };
+    let extension = |s: &SuffixInfo| match s.decode_extension() {
+        None => "none".to_owned(),
+        Some(SuffixExtension::Text(text)) => format!("text {text:?}"),
+        Some(SuffixExtension::Unknown(bytes)) => hex_bytes(bytes),
+    };
+    let crc = |s: &SuffixInfo| match s.has_valid_crc() {
+        true => format!("{:#010x}", s.expected_crc),
+        false => format!("{:#010x} (INVALID)", s.expected_crc),
+    };
+
+    print_field("USB ID", usb_id(old_suffix), usb_id(new_suffix));
+    print_field("Release number", release(old_suffix), release(new_suffix));
+    print_field(
+        "DFU version",
+        format!("{:#06x}", old_suffix.dfu_version),
+        format!("{:#06x}", new_suffix.dfu_version),
+    );
+    print_field(
+        "Suffix length",
+        format!("{} bytes", old_suffix.suffix_length),
+        format!("{} bytes", new_suffix.suffix_length),
+    );
+    print_field(
+        "Extra suffix bytes",
+        extension(old_suffix),
+        extension(new_suffix),
+    );
+    print_field("CRC", crc(old_suffix), crc(new_suffix));
+    print_field(
+        "Payload size",
+        format!("{} bytes", old_suffix.payload_length),
+        format!("{} bytes", new_suffix.payload_length),
+    );
+    print_field("SHA-256", sha256_hex(&old_data), sha256_hex(&new_data));
+
+    let (old_payload, new_payload) = (old.payload(), new.payload());
+    print_block_diff(old_payload, new_payload);
+
+    let strings = changed_strings(old_payload, new_payload, MIN_STRING_LEN);
+    for (label, strings) in [
+        ("Strings only in old", strings.removed),
+        ("Strings only in new", strings.added),
+    ] {
+        match strings.is_empty() {
+            true => println!("{label}: none"),
+            false => {
+                println!("{label}:");
+                for (offset, string) in strings {
+                    println!("  {offset:#010x}: {string}");
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Print which blocks, in the size they're transferred to the device in, differ between two
+/// payloads.
+fn print_block_diff(old: &[u8], new: &[u8]) {
+    let diff = BlockDiff::compare(old, new, XFER_DATA_SIZE);
+    println!(
+        "Payload blocks: {} of {} changed ({:.1}%), {} bytes each",
+        diff.changed_blocks(),
+        diff.total_blocks,
+        diff.percent_changed(),
+        diff.block_size
+    );
+
+    let len = old.len().max(new.len());
+    match diff.changed.is_empty() {
+        true => println!("Changed blocks: none"),
+        false => {
+            println!("Changed blocks:");
+            for run in &diff.changed {
+                let bytes = diff.byte_range(run);
+                let blocks = match run.len() {
+                    1 => format!("{}", run.start),
+                    _ => format!("{}-{}", run.start, run.end - 1),
+                };
+                println!(
+                    "  {blocks} (bytes {:#010x}-{:#010x})",
+                    bytes.start,
+                    bytes.end.min(len) - 1
+                );
+            }
+        }
+    }
+}
+
+fn upload_diff_cmd(
+    dev: &HidDevice,
+    info: &DeviceInfo,
+    path: &Path,
+    save: Option<&Path>,
+) -> Result<()> {
+    let data = read_firmware_file(path)?;
+    let file = DfuFile::from_bytes(&data)?;
+    if !file.matches_device(usb_id(info)) {
+        warn!(
+            "{} isn't meant for this device, so it's probably not what the device is running",
+            path.display()
+        );
+    }
+
+    ensure_idle(dev)?;
+    info!("Reading firmware from device; it may take several minutes");
+    let mut read_back = vec![];
+    upload(dev, &mut read_back)?;
+
+    if let Some(save) = save {
+        std::fs::write(save, &read_back)
+            .with_context(|| format!("failed to write {}", save.display()))?;
+    }
+
+    print_readback_analysis(file.payload(), &read_back);
+    Ok(())
+}
+
+fn print_readback_analysis(written: &[u8], read_back: &[u8]) {
+    println!(
+        "Written: {} bytes (SHA-256 {})",
+        written.len(),
+        sha256_hex(written)
+    );
+    println!(
+        "Read back: {} bytes (SHA-256 {})",
+        read_back.len(),
+        sha256_hex(read_back)
+    );
+    print_block_diff(written, read_back);
+
+    let analysis = ReadbackAnalysis::analyze(written, read_back, XFER_DATA_SIZE);
+    match analysis.tail {
+        Tail::SameLength => println!("Trailing data: none"),
+        Tail::Extra { len, fill: None } => println!("Trailing data: {len} extra bytes"),
+        Tail::Extra {
+            len,
+            fill: Some(fill),
+        } => println!("Trailing data: {len} extra bytes, all {fill:#04x}"),
+        Tail::Missing(len) => println!("Trailing data: read-back is {len} bytes short"),
+    }
+
+    match analysis.fill_blocks.is_empty() {
+        true => println!("Changed blocks read back as padding: none"),
+        false => {
+            println!("Changed blocks read back as padding:");
+            for (blocks, fill) in block_runs(&analysis.fill_blocks) {
+                println!("  {blocks}: all {fill:#04x}");
+            }
+        }
+    }
+
+    let shifts: Vec<_> = analysis
+        .relocations
+        .iter()
+        .map(|r| (r.block, analysis.shift(r)))
+        .collect();
+    match shifts.is_empty() {
+        true => println!("Changed blocks found elsewhere: none"),
+        false => {
+            println!("Changed blocks found elsewhere:");
+            for (blocks, shift) in block_runs(&shifts) {
+                match shift {
+                    Some(shift) => println!("  {blocks}: moved by {shift:+} bytes"),
+                    None => println!("  {blocks}: not found"),
+                }
+            }
+        }
+    }
+
+    let found = shifts.iter().filter(|(_, shift)| shift.is_some()).count();
+    let pattern = if analysis.blocks.changed.is_empty() {
+        "read-back matches what was written".to_owned()
+    } else if let Some(shift) = analysis.consistent_shift() {
+        format!("every changed block found elsewhere moved by {shift:+} bytes")
+    } else if found > 0 {
+        "changed blocks moved by different amounts".to_owned()
+    } else if shifts.is_empty() {
+        "every changed block reads back as padding".to_owned()
+    } else {
+        "none; some changed blocks appear nowhere in the read-back".to_owned()
+    };
+    println!("Consistent pattern: {pattern}");
+}
+
+/// Group `(block, value)` pairs, which must be sorted by block, into runs of consecutive blocks
+/// with the same value, formatted like "3" or "3-7".
+fn block_runs<T: PartialEq + Copy>(items: &[(usize, T)]) -> Vec<(String, T)> {
+    let mut runs: Vec<(usize, usize, T)> = vec![];
+    for &(block, value) in items {
+        match runs.last_mut() {
+            Some((_, end, last)) if *end + 1 == block && *last == value => *end = block,
+            _ => runs.push((block, block, value)),
+        }
+    }
+
+    runs.into_iter()
+        .map(|(start, end, value)| match start == end {
+            true => (start.to_string(), value),
+            false => (format!("{start}-{end}"), value),
+        })
+        .collect()
+}
+
+fn print_bundle_info(bundle: &Bundle) {
+    println!(
+        "Multi-model firmware bundle with {} files:",
+        bundle.members.len()
+    );
+    for member in &bundle.members {
+        let version = match member.suffix.firmware_version() {
+            Some(version) => version.to_string(),
+            None => format!("release {:04x}", member.suffix.release_number),
+        };
+        println!(
+            "  {}: {} ({}), {version}",
+            member.entry.file, member.entry.model, member.entry.usb_id
+        );
+    }
+}
+
+fn print_dfuse_info(payload: &[u8]) {
+    let image = match dfuse::parse(payload) {
+        Ok(image) => image,
+        Err(e) => {
+            println!("DfuSe: INVALID ({e})");
+            return;
+        }
+    };
+
+    println!(
+        "DfuSe: version {}, image size {} bytes, {} target(s)",
+        image.version,
+        image.image_size,
+        image.targets.len()
+    );
+    for target in &image.targets {
+        println!(
+            "  Target {} ({}): {} element(s)",
+            target.alternate_setting,
+            target.name.as_deref().unwrap_or("unnamed"),
+            target.elements.len()
+        );
+        for element in &target.elements {
+            println!(
+                "    {} bytes at {:#010x} (file offset {:#x})",
+                element.size, element.address, element.offset
+            );
+        }
+    }
+}
+
+/// Check if a string contains something like a dotted version number (e.g. "1.3.8").
+fn looks_like_version(s: &str) -> bool {
+    let bytes = s.as_bytes();
+    bytes
+        .windows(3)
+        .any(|w| w[0].is_ascii_digit() && w[1] == b'.' && w[2].is_ascii_digit())
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    let hex: Vec<_> = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    hex.join(" ")
+}
+
+fn load_manifest(config: &Config) -> Result<Option<Manifest>> {
+    Ok(config.manifest.as_deref().map(Manifest::load).transpose()?)
+}
+
+/// Check a firmware file's hash against the manifest named in the configuration file, if there is
+/// one. Files the manifest doesn't list are only allowed if `allow_unknown` is set, and files that
+/// claim to be a listed image but have a different hash are never allowed.
+fn check_manifest(config: &Config, firmware: &LoadedFirmware, allow_unknown: bool) -> Result<()> {
+    let Some(manifest) = load_manifest(config)? else {
+        return Ok(());
+    };
+
+    match manifest.check(&firmware.sha256, firmware.file.suffix()) {
+        Verdict::Known(image) => {
+            info!(
+                "Firmware file is known official image {} {}",
+                image.model, image.version
+            );
+        }
+        Verdict::Unknown if allow_unknown => {
+            warn!("Firmware file is not listed in manifest; writing it anyway");
+        }
+        Verdict::Unknown => bail!(
+            "firmware file is not listed in manifest; pass --allow-unknown to write it anyway"
+        ),
+        Verdict::Mismatch(image) => bail!(
+            "firmware file claims to be {} {}, but its SHA-256 hash doesn't match the manifest",
+            image.model,
+            image.version
+        ),
+    }
+
+    Ok(())
+}
+
+/// A firmware file loaded into memory by [read_firmware_file].
+type FirmwareFile = DfuFile<Cursor<Vec<u8>>>;
+
+/// A firmware file loaded by [load_firmware] to be written to a device.
+struct LoadedFirmware {
+    file: FirmwareFile,
+    /// SHA-256 hash of the firmware as given, as a lowercase hex string. For a raw image, this is
+    /// the hash of the image itself, without the DFU suffix added to it, and for a bundle it's the
+    /// hash of the file picked from it.
+    sha256: String,
+}
+
+/// Load a firmware file or bundle into memory, decompressing it if necessary. A path of `-` means
+/// standard input, which can only be read once, so callers must load each file once and keep it.
+fn read_firmware_contents(path: &Path) -> Result<Contents> {
+    if path == Path::new("-") {
+        return Ok(read_contents(std::io::stdin().lock())?);
+    }
+
+    let file =
+        std::fs::File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    Ok(read_contents(file)?)
+}
+
+/// Load a firmware file into memory like [read_firmware_contents], failing if it's a bundle.
+fn read_firmware_file(path: &Path) -> Result<Vec<u8>> {
+    match read_firmware_contents(path)? {
+        Contents::Single(data) => Ok(data),
+        Contents::Bundle(_) => Err(archive::Error::IsBundle.into()),
+    }
+}
+
+/// The device a firmware file is being loaded for, which decides what file is used if it's a
+/// bundle.
+struct FirmwareTarget<'a> {
+    /// The device's DFU-mode USB ID.
+    dfu_id: UsbId,
+    /// The device's model string. Only devices in normal mode can report it.
+    model: Option<&'a str>,
+}
+
+/// Load a firmware file into memory like [read_firmware_contents], picking the file for `target`
+/// if it's a bundle.
+fn read_firmware_for(path: &Path, target: &FirmwareTarget) -> Result<Vec<u8>> {
+    let bundle = match read_firmware_contents(path)? {
+        Contents::Single(data) => return Ok(data),
+        Contents::Bundle(bundle) => bundle,
+    };
+
+    let member = match bundle.select(target.dfu_id, target.model) {
+        Ok(member) => member,
+        Err(e @ bundle::Error::Ambiguous(_)) if target.model.is_none() => {
+            return Err(anyhow!(e).context(
+                "can't tell which firmware in bundle to use without the device's model; use \
+                `update`, which reads the model before entering DFU mode",
+            ));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    info!(
+        "Using {} ({}) from bundle {}",
+        member.entry.file,
+        member.entry.model,
+        path.display()
+    );
+    Ok(member.data.clone())
+}
+
+/// Load a firmware file into memory and parse it, failing if it's a bundle.
+fn open_firmware(path: &Path) -> Result<LoadedFirmware> {
+    let data = read_firmware_file(path)?;
+    Ok(LoadedFirmware {
+        sha256: sha256_hex(&data),
+        file: DfuFile::new(Cursor::new(data))?,
+    })
+}
+
+/// Make sure a loaded firmware file is intact and meant for a device with the given ID, and log
+/// anything unusual about it.
+fn check_firmware(file: &FirmwareFile, dev_id: UsbId, wildcard_fw: bool) -> Result<()> {
+    validate_firmware(file, dev_id, wildcard_fw)?;
+    log_file_warnings(file.suffix());
+    Ok(())
+}
+
+/// The checks done by [check_firmware], without logging anything, so they can be run against many
+/// devices.
+fn validate_firmware<R: Read + Seek>(
+    file: &DfuFile<R>,
+    dev_id: UsbId,
+    wildcard_fw: bool,
+) -> Result<()> {
+    file.ensure_valid_crc()?;
+    ensure_file_matches(file, dev_id, wildcard_fw)
+}
+
+/// Load a firmware file into memory and parse it, picking the file for `target` if it's a bundle.
+/// If `raw` is given, the file is a raw image without a DFU suffix, and is wrapped in a suffix for
+/// that device.
+fn load_firmware(
+    path: &Path,
+    raw: Option<&DeviceIds>,
+    target: &FirmwareTarget,
+) -> Result<LoadedFirmware> {
+    let data = read_firmware_for(path, target)?;
+    let sha256 = sha256_hex(&data);
+    let data = match raw {
+        None => data,
+        Some(device) => {
+            let mut wrapped = vec![];
+            wrap_raw_firmware(&mut wrapped, &data, Some(device), None)?;
+            wrapped
+        }
+    };
+
+    Ok(LoadedFirmware {
+        file: DfuFile::new(Cursor::new(data))?,
+        sha256,
+    })
+}
+
+/// Write `payload` to `out` with a DFU suffix for `device`, or a suffix with a wildcard USB ID if
+/// `device` is [None]. Refuses payloads that already have a DFU suffix.
+fn wrap_raw_firmware(
+    out: &mut impl std::io::Write,
+    payload: &[u8],
+    device: Option<&DeviceIds>,
+    version: Option<&FirmwareVersion>,
+) -> Result<()> {
+    if DfuFile::from_bytes(payload).is_ok() {
+        bail!("file already has a DFU suffix; it doesn't need converting");
+    }
+
+    let release = match version {
+        None => OptionalId(None),
+        Some(v) => match v.to_bcd() {
+            Some(bcd) => OptionalId(Some(bcd)),
+            None => bail!("version {v} can't be stored as a DFU release number"),
+        },
+    };
+    let suffix = match device {
+        Some(device) => SuffixInfo::new(
+            device.dfu_mode.vid.into(),
+            device.dfu_mode.pid.into(),
+            release,
+        ),
+        None => SuffixInfo::new(OptionalId(None), OptionalId(None), release),
+    };
+
+    dfu_file::write(out, payload, &suffix)?;
+    Ok(())
+}
+
+/// Find a device in the table of compatible devices, for options that name one.
+fn device_by_name(name_or_id: &str) -> Result<&'static DeviceIds> {
+    find_device(name_or_id).ok_or_else(|| {
+        let known: Vec<_> = COMPATIBLE_DEVICES
+            .iter()
+            .map(|d| format!("{} ({})", d.name, d.dfu_mode))
+            .collect();
+        anyhow!(
+            "unknown device {name_or_id:?}; known devices are {}",
+            known.join(", ")
+        )
+    })
+}
+
+fn convert_cmd(
+    input: &Path,
+    output: &Path,
+    device: Option<&DeviceIds>,
+    version: Option<&FirmwareVersion>,
+) -> Result<()> {
+    let payload = read_firmware_file(input)?;
+    if device.is_none() {
+        warn!("Output file will have a wildcard USB ID; `download` will require -w to write it");
+    }
+
+    let mut out = vec![];
+    wrap_raw_firmware(&mut out, &payload, device, version)?;
+    std::fs::write(output, out).with_context(|| format!("failed to write {}", output.display()))?;
+
+    match device {
+        Some(device) => info!(
+            "Wrote {} for {} ({})",
+            output.display(),
+            device.name,
+            device.dfu_mode
+        ),
+        None => info!("Wrote {}", output.display()),
+    }
+    Ok(())
+}
+
+/// Log any non-fatal problems found while parsing a firmware file that's about to be written.
+fn log_file_warnings(suffix: &SuffixInfo) {
     if suffix.vendor_id.0.is_none() || suffix.product_id.0.is_none() {
         warn!(
-            "Update's USB ID ({:04x}:{:04x}) is incomplete; can't guarantee it's for this device",
-            suffix.vendor_id, suffix.product_id,
+            "Update's USB ID ({:04x}:{:04x}) is incomplete; can't guarantee it's for this device",
+            suffix.vendor_id, suffix.product_id,
+        );
+    } else {
+        info!("Update verified to be for selected device");
+    }
+
+    for warning in &suffix.warnings {
+        // Incomplete IDs were dealt with above.
+        if *warning != SuffixWarning::WildcardId {
+            warn!("Firmware file looks unusual: {warning}");
+        }
+    }
+}
+
+/// Write a firmware file previously checked by [check_firmware] to a device in DFU mode.
+fn write_firmware(dev: &HidDevice, file: &mut FirmwareFile) -> Result<()> {
+    ensure_idle(dev)?;
+
+    info!("Beginning firmware download; it may take several minutes; do not unplug device");
+    download(dev, &mut file.payload_reader()?)?;
+
+    Ok(())
+}
+
+/// Identifying details of a device being updated, which must outlive the device's [DeviceInfo]
+/// since the device is re-enumerated when it changes modes.
+struct UpdateTarget {
+    id: UsbId,
+    serial: Option<String>,
+}
+
+impl UpdateTarget {
+    fn new(info: &DeviceInfo) -> Self {
+        Self {
+            id: usb_id(info),
+            serial: info.serial_number().map(str::to_owned),
+        }
+    }
+
+    fn name(&self) -> &str {
+        self.serial.as_deref().unwrap_or("INVALID")
+    }
+
+    fn audit_device(&self) -> AuditDevice {
+        AuditDevice {
+            usb_id: self.id,
+            serial: self.serial.clone(),
+        }
+    }
+}
+
+/// Run the full update sequence on a device in normal mode: enter DFU mode, wait for the device to
+/// reappear in DFU mode, write the firmware, and leave DFU mode.
+fn update_device(
+    hidapi: &mut HidApi,
+    dev: HidDevice,
+    target: &UpdateTarget,
+    file: &mut FirmwareFile,
+    wildcard_fw: bool,
+    backup: Option<Backup>,
+) -> Result<()> {
+    const DFU_TIMEOUT: Duration = Duration::from_secs(30);
+    const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+    let Some(ids) = find_device_ids(target.id) else {
+        bail!(
+            "don't know what USB ID device {} uses in DFU mode",
+            target.id
+        );
+    };
+
+    // Check the file before touching the device, so a bad file doesn't leave it stuck in DFU mode.
+    check_firmware(file, ids.dfu_mode, wildcard_fw)?;
+
+    let is_dfu_device = |d: &DeviceInfo| {
+        usb_id(d) == ids.dfu_mode
+            && !matches!(
+                identify_device(ids.dfu_mode, d.usage_page()),
+                DeviceCompat::Incompatible
+            )
+    };
+    // Remember which devices were already in DFU mode, so we can tell which one is ours if it
+    // doesn't keep its USB serial number.
+    let already_in_dfu: HashSet<_> = hidapi
+        .device_list()
+        .filter(|d| is_dfu_device(d))
+        .map(|d| d.path().to_owned())
+        .collect();
+
+    info!("{}: entering DFU mode", target.name());
+    enter_dfu(&dev)?;
+    drop(dev);
+
+    let start = Instant::now();
+    let dfu_dev = loop {
+        if start.elapsed() > DFU_TIMEOUT {
+            bail!(
+                "device {} did not reappear in DFU mode within {DFU_TIMEOUT:?}; it may still be \
+                in DFU mode, so find it with `bose-dfu list` and run `bose-dfu leave-dfu` on it \
+                to restore its normal firmware",
+                target.name()
+            );
+        }
+        sleep(POLL_INTERVAL);
+        hidapi.refresh_devices()?;
+
+        // Look for the device by its USB serial number first. Failing that, accept the only device
+        // that has appeared in DFU mode since we started, if there is exactly one.
+        let dfu_devices: Vec<_> = hidapi.device_list().filter(|d| is_dfu_device(d)).collect();
+        let by_serial = target.serial.as_ref().and_then(|serial| {
+            dfu_devices
+                .iter()
+                .find(|d| d.serial_number() == Some(serial))
+        });
+        let new_devices: Vec<_> = dfu_devices
+            .iter()
+            .filter(|d| !already_in_dfu.contains(d.path()))
+            .collect();
+        let found = match (by_serial, &new_devices[..]) {
+            (Some(info), _) => Some(*info),
+            (None, [info]) => {
+                if target.serial.is_some() {
+                    warn!(
+                        "{}: device reappeared in DFU mode with USB serial number {}",
+                        target.name(),
+                        info.serial_number().unwrap_or("INVALID")
+                    );
+                }
+                Some(**info)
+            }
+            (None, _) => None,
+        };
+
+        if let Some(info) = found {
+            break info
+                .open_device(hidapi)
+                .context("failed to open device in DFU mode; do you have permission?")?;
+        }
+    };
+
+    if let Some(backup) = backup {
+        back_up_firmware(
+            &dfu_dev,
+            backup.store,
+            target.serial.as_deref(),
+            ids.dfu_mode,
+            backup.version,
+        )
+        .context("backup failed; device is still in DFU mode")?;
+    }
+
+    write_firmware(&dfu_dev, file)?;
+
+    info!("{}: leaving DFU mode", target.name());
+    leave_dfu(&dfu_dev)?;
+
+    Ok(())
+}
+
+/// Options for the `watch` subcommand.
+struct WatchOptions {
+    file: std::path::PathBuf,
+    wildcard_fw: bool,
+    model: Option<String>,
+    fw_version: Option<FirmwareVersion>,
+    interval: Duration,
+    allow_unknown: bool,
+    checks: VersionChecks,
+}
+
+/// Poll for devices matching `spec` forever, updating each one whose firmware version differs from
+/// the one `options.file` installs.
+fn watch_cmd(
+    hidapi: &mut HidApi,
+    config: &Config,
+    spec: &DeviceSpec,
+    options: &WatchOptions,
+    auditor: &Auditor,
+) -> Result<()> {
+    let mut firmware = open_firmware(&options.file)?;
+    check_manifest(config, &firmware, options.allow_unknown)?;
+    let fw_version = installed_version(&firmware.file, options.fw_version.as_ref())?;
+
+    // Devices we've already looked at, keyed by serial number (or path, if they don't have one).
+    // We forget a device once it's disconnected, so that plugging it back in rechecks it.
+    let mut seen = HashSet::new();
+    // Devices we've written firmware to. Unlike `seen`, we never forget these: if the version
+    // still doesn't match after an update, the version we expect is probably wrong, and updating
+    // the device again won't help.
+    let mut updated = HashSet::new();
+
+    info!("Watching for devices to update to {fw_version}; press Ctrl-C to stop");
+    loop {
+        hidapi.refresh_devices()?;
+
+        let present: HashSet<String> = hidapi.device_list().map(device_key).collect();
+        seen.retain(|key| present.contains(key));
+
+        let new_devices: Vec<_> = spec
+            .candidates(hidapi, config)?
+            .into_iter()
+            .filter(|(d, _)| !seen.contains(&device_key(d)))
+            .map(|(d, risks)| (d.clone(), risks))
+            .collect();
+
+        for (info, risks) in new_devices {
+            let key = device_key(&info);
+            seen.insert(key.clone());
+
+            let target = UpdateTarget::new(&info);
+            let check_and_update = || -> Result<()> {
+                spec.check_risks(risks)?;
+
+                let dev = info
+                    .open_device(hidapi)
+                    .context("failed to open device; do you have permission?")?;
+
+                if let Some(ref model) = options.model {
+                    let actual = read_info_field(&dev, InfoField::DeviceModel)?;
+                    if actual != *model {
+                        info!("{}: skipping, model is {actual}", target.name());
+                        return Ok(());
+                    }
+                }
+
+                let current = read_info_field(&dev, InfoField::CurrentFirmware)?;
+                if current
+                    .parse::<FirmwareVersion>()
+                    .is_ok_and(|v| v.is_same_release(&fw_version))
+                {
+                    info!("{}: already running {current}", target.name());
+                    return Ok(());
+                }
+
+                if updated.contains(&key) {
+                    warn!(
+                        "{}: still running {current} after update; is --fw-version right?",
+                        target.name()
+                    );
+                    return Ok(());
+                }
+
+                let result = options.checks.check(&current, &fw_version).and_then(|()| {
+                    info!("{}: running {current}, updating", target.name());
+                    updated.insert(key);
+                    update_device(
+                        hidapi,
+                        dev,
+                        &target,
+                        &mut firmware.file,
+                        options.wildcard_fw,
+                        None,
+                    )
+                });
+                let mut entry = auditor.entry("watch", vec![target.audit_device()], &result);
+                entry.file_sha256 = Some(firmware.sha256.clone());
+                auditor.record(entry, result)?;
+                record_update(
+                    history_path(config).as_deref(),
+                    &target,
+                    Some(&current),
+                    &options.file,
+                    &firmware,
+                    Some(&fw_version),
+                    None,
+                );
+                info!("{}: update complete", target.name());
+                Ok(())
+            };
+
+            if let Err(e) = check_and_update() {
+                error!("{}: {e:#}", target.name());
+            }
+        }
+
+        sleep(options.interval);
+    }
+}
+
+/// Get the USB ID of an enumerated device.
+fn usb_id(info: &DeviceInfo) -> UsbId {
+    UsbId {
+        vid: info.vendor_id(),
+        pid: info.product_id(),
+    }
+}
+
+/// A key that identifies a device across reconnections, where possible.
+fn device_key(info: &DeviceInfo) -> String {
+    match info.serial_number() {
+        Some(serial) => serial.to_owned(),
+        None => info.path().to_string_lossy().into_owned(),
+    }
+}
+
+/// Get the DFU-mode USB ID of a device, given its ID in either mode. Unknown devices are assumed to
+/// keep the same ID.
+fn dfu_mode_id(id: UsbId) -> UsbId {
+    find_device_ids(id).map_or(id, |ids| ids.dfu_mode)
+}
+
+/// Where the update history is kept: the file named in the configuration file, or the default one.
+fn history_path(config: &Config) -> Option<std::path::PathBuf> {
+    config.history.clone().or_else(History::default_path)
+}
+
+/// Add a successful update of `target` with `firmware`, loaded from `path`, to the update history
+/// at `history_path`. Failing to only produces a warning, since the update itself succeeded.
+fn record_update(
+    history_path: Option<&Path>,
+    target: &UpdateTarget,
+    previous: Option<&str>,
+    path: &Path,
+    firmware: &LoadedFirmware,
+    version: Option<&FirmwareVersion>,
+    verification: Option<Verification>,
+) {
+    let Some(history_path) = history_path else {
+        warn!("Can't find a data directory; not recording update in history");
+        return;
+    };
+
+    let path = match path == Path::new("-") {
+        true => None,
+        false => std::path::absolute(path).ok(),
+    };
+    let mut entry = HistoryEntry::new(
+        target.serial.as_deref(),
+        dfu_mode_id(target.id),
+        previous,
+        installed_version(&firmware.file, version).ok().as_ref(),
+        &firmware.sha256,
+        path.as_deref(),
+    );
+    entry.verification = verification;
+    if let Err(e) = History::append(history_path, entry) {
+        warn!("Failed to record update in history: {:#}", anyhow!(e));
+    }
+}
+
+/// Reinstall the firmware a device was running before its last recorded update, found either in
+/// the update history or in the firmware library. The hash of the file chosen is stored in `hash`.
+fn rollback_cmd(
+    hidapi: &mut HidApi,
+    config: &Config,
+    dev: HidDevice,
+    target: &UpdateTarget,
+    wildcard_fw: bool,
+    allow_unknown: bool,
+    hash: &mut Option<String>,
+) -> Result<()> {
+    let Some(serial) = target.serial.as_deref() else {
+        bail!("device has no USB serial number, so its update history can't be found");
+    };
+    let Some(path) = history_path(config) else {
+        bail!("can't find a data directory; set history in the configuration file");
+    };
+    let history = History::load(&path)?;
+    let Some(last) = history.last_for(serial) else {
+        bail!("no recorded updates of device {serial}");
+    };
+    let Some(previous) = &last.previous_version else {
+        bail!(
+            "device {serial} was last updated in DFU mode at {}, so its previous firmware \
+            version is unknown",
+            last.timestamp
+        );
+    };
+    let previous: FirmwareVersion = previous
+        .parse()
+        .with_context(|| format!("invalid previous version {previous:?} in update history"))?;
+
+    let current = read_info_field(&dev, InfoField::CurrentFirmware)?;
+    let current_version = current.parse::<FirmwareVersion>().ok();
+    if current_version
+        .as_ref()
+        .is_some_and(|v| v.is_same_release(&previous))
+    {
+        bail!("device {serial} is already running version {current}");
+    }
+    if !current_version.as_ref().is_some_and(|v| last.installed(v)) {
+        warn!(
+            "Device is running {current}, not the version its last recorded update installed; \
+            it may have been updated by something else since"
+        );
+    }
+
+    let model = read_info_field(&dev, InfoField::DeviceModel)?;
+    let fw_target = FirmwareTarget {
+        dfu_id: dfu_mode_id(target.id),
+        model: Some(&model),
+    };
+    let (path, mut firmware) = find_rollback_firmware(config, &history, &fw_target, &previous)?;
+    *hash = Some(firmware.sha256.clone());
+    check_manifest(config, &firmware, allow_unknown)?;
+
+    info!("Rolling back from {current} to {previous}");
+    update_device(hidapi, dev, target, &mut firmware.file, wildcard_fw, None)?;
+    record_update(
+        history_path(config).as_deref(),
+        target,
+        Some(&current),
+        &path,
+        &firmware,
+        Some(&previous),
+        None,
+    );
+    Ok(())
+}
+
+/// Find a firmware file with the given version for a rollback. Files recorded in the update
+/// history are preferred, as long as they're unchanged; otherwise the firmware library is used.
+fn find_rollback_firmware(
+    config: &Config,
+    history: &History,
+    target: &FirmwareTarget,
+    version: &FirmwareVersion,
+) -> Result<(std::path::PathBuf, LoadedFirmware)> {
+    for entry in history.installs_of(target.dfu_id, version) {
+        let Some(path) = &entry.file else {
+            continue;
+        };
+        match load_firmware(path, None, target) {
+            Ok(firmware) if firmware.sha256 == entry.sha256 => {
+                info!("Using firmware file {} from update history", path.display());
+                return Ok((path.clone(), firmware));
+            }
+            Ok(_) => warn!(
+                "{} has changed since it was installed; skipping",
+                path.display()
+            ),
+            Err(e) => warn!("Skipping {} from update history: {e:#}", path.display()),
+        }
+    }
+
+    let path = choose_firmware(config, None, Some(version.clone()), target.dfu_id)?;
+    let firmware = load_firmware(&path, None, target)?;
+    Ok((path, firmware))
+}
+
+/// Where to log operations that change a device's state, and who to attribute them to. Nothing is
+/// logged unless `audit_log` is set in the configuration file.
+struct Auditor {
+    log: Option<AuditLog>,
+    user: String,
+}
+
+impl Auditor {
+    fn new(config: &Config) -> Self {
+        let user = std::env::var("USER")
+            .or_else(|_| std::env::var("USERNAME"))
+            .unwrap_or_else(|_| "unknown".to_owned());
+        Self {
+            log: config.audit_log.as_deref().map(AuditLog::new),
+            user,
+        }
+    }
+
+    /// Make sure operations can be recorded before performing any, so none go unrecorded.
+    fn check(&self) -> Result<()> {
+        let Some(log) = &self.log else {
+            return Ok(());
+        };
+        log.entries()
+            .context("refusing to change device state without a working audit log")?;
+        Ok(())
+    }
+
+    /// Describe an operation on `devices` that finished with `result`.
+    fn entry(&self, command: &str, devices: Vec<AuditDevice>, result: &Result<()>) -> AuditEntry {
+        let outcome = match result {
+            Ok(()) => Outcome::Ok,
+            Err(_) => Outcome::Failed,
+        };
+
+        let mut entry = AuditEntry::new(&self.user, command, devices, outcome);
+        if let Err(e) = result {
+            let variant = leaf_variant(e.as_ref()).unwrap_or(OTHER_ERROR_VARIANT);
+            entry.error_variant = Some(variant.to_owned());
+            entry.error = Some(format!("{e:#}"));
+        }
+        entry
+    }
+
+    /// Add an entry for an operation that finished with `result` to the audit log, and pass
+    /// `result` on. Failing to add the entry is an error too, unless the operation already failed.
+    fn record(&self, entry: AuditEntry, result: Result<()>) -> Result<()> {
+        match (result, self.append(entry)) {
+            (result, Ok(())) => result,
+            (Err(e), Err(audit_err)) => {
+                warn!("Failed to record operation in audit log: {audit_err:#}");
+                Err(e)
+            }
+            (Ok(()), Err(audit_err)) => {
+                Err(audit_err.context("operation succeeded, but couldn't be recorded in audit log"))
+            }
+        }
+    }
+
+    /// Add `entry` to the audit log, if there is one.
+    fn append(&self, entry: AuditEntry) -> Result<()> {
+        match &self.log {
+            Some(log) => Ok(log.append(entry)?),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Describe a device for the audit log.
+fn audit_device(info: &DeviceInfo) -> AuditDevice {
+    AuditDevice {
+        usb_id: usb_id(info),
+        serial: info.serial_number().map(str::to_owned),
+    }
+}
+
+/// Check every entry in the audit log, failing at the first sign of tampering.
+fn audit_verify_cmd(log: &AuditLog) -> Result<()> {
+    let count = log.verify()?;
+    println!("OK: {count} entries in {}", log.path().display());
+    Ok(())
+}
+
+/// Figure out what firmware version a device will report after `file` is written to it: either
+/// `explicit`, if given, or the file's release number.
+fn installed_version(
+    file: &FirmwareFile,
+    explicit: Option<&FirmwareVersion>,
+) -> Result<FirmwareVersion> {
+    if let Some(version) = explicit {
+        return Ok(version.clone());
+    }
+
+    let suffix = file.suffix();
+    match suffix.firmware_version() {
+        Some(version) => Ok(version),
+        None => bail!(
+            "can't tell what firmware version the file contains from its release number ({:04x}); \
+            pass --fw-version to say",
+            suffix.release_number,
+        ),
+    }
+}
+
+impl VersionChecks {
+    /// Make sure we're allowed to replace the firmware version `current` (as reported by the
+    /// device) with `new`, warning if we're allowed but the user should be aware.
+    fn check(&self, current: &str, new: &FirmwareVersion) -> Result<()> {
+        let current = match current.parse::<FirmwareVersion>() {
+            Ok(v) => v,
+            Err(e) if self.allow_downgrade => {
+                warn!("{e}; not checking for downgrade");
+                return Ok(());
+            }
+            Err(e) => {
+                return Err(e).context("pass --allow-downgrade to skip the downgrade check");
+            }
+        };
+
+        // The file's version usually has no build number, so it can't be told apart from builds
+        // of the same release the device might report.
+        let order = match new.is_same_release(&current) {
+            true => Ordering::Equal,
+            false => new.compare(&current),
+        };
+        match order {
+            Ordering::Greater => Ok(()),
+            Ordering::Equal if self.reinstall => {
+                warn!("Reinstalling firmware version {new}");
+                Ok(())
+            }
+            Ordering::Equal => {
+                bail!("device is already running {current}; pass --reinstall to write it again")
+            }
+            Ordering::Less if self.allow_downgrade => {
+                warn!("Downgrading firmware from {current} to {new}");
+                Ok(())
+            }
+            Ordering::Less => bail!(
+                "{new} is older than the device's current firmware ({current}); pass \
+                --allow-downgrade to install it anyway"
+            ),
+        }
+    }
+}
+
+/// What `reconcile` intends to do with one device.
+enum ReconcileStep {
+    UpToDate {
+        current: String,
+    },
+    NoPolicy {
+        model: String,
+    },
+    Update {
+        current: String,
+        target: FirmwareVersion,
+        path: std::path::PathBuf,
+        /// The file as loaded and checked while planning, which is what gets written, even if the
+        /// file on disk changes before the update is confirmed.
+        firmware: Box<LoadedFirmware>,
+    },
+}
+
+/// Options for the `reconcile` subcommand.
+struct ReconcileOptions {
+    wildcard_fw: bool,
+    yes: bool,
+    allow_unknown: bool,
+    checks: VersionChecks,
+}
+
+/// Compare every device matching `spec` against a policy file, print what needs to change, and,
+/// once confirmed, update the devices that are out of date.
+fn reconcile_cmd(
+    hidapi: &mut HidApi,
+    config: &Config,
+    spec: &DeviceSpec,
+    policy_path: &Path,
+    options: &ReconcileOptions,
+    auditor: &Auditor,
+) -> Result<()> {
+    let policy = Policy::load(policy_path)?;
+
+    let devices: Vec<_> = spec
+        .candidates(hidapi, config)?
+        .into_iter()
+        .map(|(d, risks)| (d.clone(), risks))
+        .collect();
+    if devices.is_empty() {
+        return Err(MatchError::NoDevices.into());
+    }
+
+    let mut plan = vec![];
+    for (info, risks) in devices {
+        let target = UpdateTarget::new(&info);
+        let step = spec
+            .check_risks(risks)
+            .and_then(|()| plan_reconcile(hidapi, config, &policy, &info, options));
+        match step {
+            Ok(ReconcileStep::UpToDate { current }) => {
+                println!("{}: up to date ({current})", target.name())
+            }
+            Ok(ReconcileStep::NoPolicy { model }) => {
+                println!("{}: no policy for model {model:?}; skipping", target.name())
+            }
+            Ok(ReconcileStep::Update {
+                current,
+                target: version,
+                path,
+                firmware,
+            }) => {
+                println!(
+                    "{}: will update {current} -> {version} using {}",
+                    target.name(),
+                    path.display()
+                );
+                plan.push((info, target, path, firmware, current, version));
+            }
+            Err(e) => println!("{}: skipping: {e:#}", target.name()),
+        }
+    }
+
+    if plan.is_empty() {
+        info!("No devices need updating");
+        return Ok(());
+    }
+
+    if !options.yes && !confirm(&format!("Update {} devices?", plan.len()))? {
+        bail!("update cancelled");
+    }
+
+    let mut results = vec![];
+    for (info, target, path, mut firmware, current, version) in plan {
+        let result = info
+            .open_device(hidapi)
+            .context("failed to open device; do you have permission?")
+            .and_then(|dev| {
+                update_device(
+                    hidapi,
+                    dev,
+                    &target,
+                    &mut firmware.file,
+                    options.wildcard_fw,
+                    None,
+                )?;
+                record_update(
+                    history_path(config).as_deref(),
+                    &target,
+                    Some(&current),
+                    &path,
+                    &firmware,
+                    Some(&version),
+                    None,
+                );
+                Ok(())
+            });
+
+        let mut entry = auditor.entry("reconcile", vec![target.audit_device()], &result);
+        entry.file_sha256 = Some(firmware.sha256.clone());
+        let result = auditor.record(entry, result);
+        results.push((target.name().to_owned(), result.map(|()| None)));
+    }
+
+    print_update_summary(&results)
+}
+
+/// Decide what `reconcile` should do with one device in normal mode.
+fn plan_reconcile(
+    hidapi: &HidApi,
+    config: &Config,
+    policy: &Policy,
+    info: &DeviceInfo,
+    options: &ReconcileOptions,
+) -> Result<ReconcileStep> {
+    let dev = info
+        .open_device(hidapi)
+        .context("failed to open device; do you have permission?")?;
+    let model = read_info_field(&dev, InfoField::DeviceModel)?;
+    let current = read_info_field(&dev, InfoField::CurrentFirmware)?;
+
+    let Some(entry) = policy.find(&model, usb_id(info)) else {
+        return Ok(ReconcileStep::NoPolicy { model });
+    };
+
+    let firmware = open_firmware(&entry.file)?;
+    check_manifest(config, &firmware, options.allow_unknown)?;
+    let target = installed_version(&firmware.file, entry.version.as_ref())?;
+    if current
+        .parse::<FirmwareVersion>()
+        .is_ok_and(|v| v.is_same_release(&target))
+    {
+        return Ok(ReconcileStep::UpToDate { current });
+    }
+
+    options.checks.check(&current, &target)?;
+    Ok(ReconcileStep::Update {
+        current,
+        target,
+        path: entry.file.clone(),
+        firmware: Box::new(firmware),
+    })
+}
+
+/// Ask the user a yes/no question, defaulting to no. Fails if there's no user to ask.
+fn confirm(question: &str) -> Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        bail!("not running interactively; pass --yes to proceed without confirmation");
+    }
+
+    let mut rl = DefaultEditor::new()?;
+    match rl.readline(&format!("{question} [y/N] ")) {
+        Ok(answer) => Ok(answer.trim().eq_ignore_ascii_case("y")),
+        Err(ReadlineError::Interrupted | ReadlineError::Eof) => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Write the same firmware to several devices in parallel, then print a summary of which devices
+/// succeeded. The file is validated against every device before any of them are written to.
+fn batch_download_cmd(
+    devices: Vec<(HidDevice, &DeviceInfo)>,
+    path: &Path,
+    mut firmware: LoadedFirmware,
+    options: &DownloadOptions,
+    auditor: &Auditor,
+) -> Result<()> {
+    let hash = firmware.sha256.clone();
+    let check = || -> Result<()> {
+        for (_, info) in &devices {
+            validate_firmware(&firmware.file, usb_id(info), options.wildcard_fw).with_context(
+                || {
+                    format!(
+                        "can't update device {}",
+                        info.serial_number().unwrap_or("INVALID")
+                    )
+                },
+            )?;
+        }
+        Ok(())
+    };
+    let checked = check();
+    if checked.is_err() {
+        // Nothing was written, but record the attempt against every device.
+        let audit_devices = devices.iter().map(|(_, info)| audit_device(info)).collect();
+        let mut entry = auditor.entry("download", audit_devices, &checked);
+        entry.file_sha256 = Some(hash);
+        return auditor.record(entry, checked);
+    }
+    log_file_warnings(firmware.file.suffix());
+
+    // Every thread needs its own reader, so just load the whole payload up front.
+    let payload = firmware.file.payload_bytes()?;
+
+    info!("Beginning firmware download; it may take several minutes; do not unplug devices");
+    let results: Vec<(String, Result<Option<Verification>>)> = std::thread::scope(|scope| {
+        let threads: Vec<_> = devices
+            .into_iter()
+            .map(|(dev, info)| {
+                let serial = info.serial_number().unwrap_or("INVALID").to_owned();
+                let target = UpdateTarget::new(info);
+                let payload = &payload;
+                let thread = scope.spawn({
+                    let serial = serial.clone();
+                    let usb_serial = info.serial_number();
+                    let id = usb_id(info);
+                    move || {
+                        if let Some(ref store) = options.backup {
+                            back_up_firmware(&dev, store, usb_serial, id, None)?;
+                        }
+                        download_with_log(&dev, &serial, payload)?;
+                        match options.verify {
+                            true => verify_download(&dev, &serial, id, payload).map(Some),
+                            false => Ok(None),
+                        }
+                    }
+                });
+                (serial, target, thread)
+            })
+            .collect();
+
+        threads
+            .into_iter()
+            .map(|(serial, target, thread)| {
+                let (result, verification) = match thread.join() {
+                    Ok(Ok(verification)) => (Ok(()), verification),
+                    Ok(Err(e)) => (Err(e), None),
+                    Err(_) => (Err(anyhow!("update thread panicked")), None),
+                };
+                if result.is_ok() {
+                    record_update(
+                        options.history.as_deref(),
+                        &target,
+                        None,
+                        path,
+                        &firmware,
+                        None,
+                        verification,
+                    );
+                }
+
+                let mut entry = auditor.entry("download", vec![target.audit_device()], &result);
+                entry.file_sha256 = Some(hash.clone());
+                let result = auditor.record(entry, result);
+                (serial, result.map(|()| verification))
+            })
+            .collect()
+    });
+
+    print_update_summary(&results)
+}
+
+/// Print a table of which devices were updated successfully, and whether they were verified if
+/// that was asked for, keyed by serial number. Returns an error if any of them weren't updated.
+fn print_update_summary(results: &[(String, Result<Option<Verification>>)]) -> Result<()> {
+    let width = results.iter().map(|(s, _)| s.len()).max().unwrap_or(0);
+    println!("{:width$}  RESULT", "SERIAL");
+    for (serial, result) in results {
+        match result {
+            Ok(None) => println!("{serial:width$}  ok"),
+            Ok(Some(verification)) => println!("{serial:width$}  ok, {verification}"),
+            Err(e) => println!("{serial:width$}  FAILED: {e:#}"),
+        }
+    }
+
+    let unverified = results
+        .iter()
+        .filter(|(_, r)| matches!(r, Ok(Some(Verification::Unverified))))
+        .count();
+    if unverified > 0 {
+        warn!(
+            "{unverified} of {} devices were updated but couldn't be verified",
+            results.len()
         );
+    }
 
-        if !wildcard_fw {
-            bail!("to write firmware with an incomplete USB ID, you must pass -w");
-        }
-    } else {
-        info!("Update verified to be for selected device");
+    let failures = results.iter().filter(|(_, r)| r.is_err()).count();
+    if failures > 0 {
+        bail!("{failures} of {} devices failed to update", results.len());
     }
 
+    Ok(())
+}
+
+/// Write a firmware payload to one device, logging progress prefixed by the device's serial.
+fn download_with_log(dev: &HidDevice, serial: &str, payload: &[u8]) -> Result<()> {
+    const PROGRESS_STEP: u64 = 10; // Percent
+
     ensure_idle(dev)?;
 
-    info!("Beginning firmware download; it may take several minutes; do not unplug device");
-    download(dev, &mut file.by_ref().take(suffix.payload_length))?;
+    let total = payload.len() as u64;
+    let mut next_report = PROGRESS_STEP;
+    download_with_progress(dev, &mut &payload[..], |written| {
+        let percent = (written * 100).checked_div(total).unwrap_or(100);
+        if percent >= next_report {
+            info!("{serial}: {percent}% written");
+            next_report = (percent / PROGRESS_STEP + 1) * PROGRESS_STEP;
+        }
+    })?;
+
+    info!("{serial}: done");
+    Ok(())
+}
+
+/// Make sure a firmware file is meant for the given device, returning an error if it's not. A file
+/// with a wildcard USB ID is only allowed if `wildcard_fw` is set. Doesn't check the CRC.
+fn ensure_file_matches<R: Read + Seek>(
+    file: &DfuFile<R>,
+    dev_id: UsbId,
+    wildcard_fw: bool,
+) -> Result<()> {
+    let suffix = file.suffix();
+    if suffix.is_dfuse() {
+        bail!(
+            "this is a DfuSe file, which is meant for STM32 bootloaders; Bose devices can't accept it"
+        );
+    }
+
+    if !file.matches_device(dev_id) {
+        bail!(
+            "this file is not for the selected device: file for {:04x}:{:04x}, device is {}",
+            suffix.vendor_id,
+            suffix.product_id,
+            dev_id
+        );
+    }
+
+    if (suffix.vendor_id.0.is_none() || suffix.product_id.0.is_none()) && !wildcard_fw {
+        bail!(
+            "to write firmware with an incomplete USB ID ({:04x}:{:04x}), you must pass -w",
+            suffix.vendor_id,
+            suffix.product_id
+        );
+    }
 
     Ok(())
 }
@@ -270,7 +2950,12 @@ fn download_cmd(dev: &HidDevice, info: &DeviceInfo, path: &Path, wildcard_fw: bo
 impl DeviceSpec {
     /// If we match the given device, return a [DeviceRisks] with details on the match. Otherwise,
     /// return [None].
-    fn match_dev(&self, device: &DeviceInfo) -> Option<DeviceRisks> {
+    fn match_dev(
+        &self,
+        hidapi: &HidApi,
+        config: &Config,
+        device: &DeviceInfo,
+    ) -> Option<DeviceRisks> {
         let dev_id = UsbId {
             vid: device.vendor_id(),
             pid: device.product_id(),
@@ -301,49 +2986,265 @@ impl DeviceSpec {
             return None;
         }
 
+        if let Some(ref x) = self.name
+            && device.serial_number().and_then(|s| config.nickname_for(s)) != Some(x)
+        {
+            return None;
+        }
+
+        if let Some(ref x) = self.product
+            && !device
+                .product_string()
+                .is_some_and(|p| p.to_lowercase().contains(&x.to_lowercase()))
+        {
+            return None;
+        }
+
+        if let Some(ref x) = self.path
+            && device.path().to_string_lossy() != *x
+        {
+            return None;
+        }
+
+        // Check this last, since it requires talking to the device. Only the normal firmware
+        // speaks TAP, so don't even try on devices that could be in any other mode.
+        if let Some(ref x) = self.hw_serial {
+            if mode != DeviceMode::Normal {
+                return None;
+            }
+
+            let open = device.open_device(hidapi).ok()?;
+            if read_info_field(&open, InfoField::SerialNumber)
+                .ok()
+                .as_ref()
+                != Some(x)
+            {
+                return None;
+            }
+        }
+
         Some(DeviceRisks {
             untested,
             ambiguous_mode,
         })
     }
 
-    fn get_device<'a>(&self, hidapi: &'a HidApi) -> Result<(HidDevice, &'a DeviceInfo)> {
-        let mut candidates = hidapi
-            .device_list()
-            .filter_map(|d| self.match_dev(d).map(|r| (d, r)));
+    fn get_device<'a>(
+        &self,
+        hidapi: &'a HidApi,
+        config: &Config,
+    ) -> Result<(HidDevice, &'a DeviceInfo)> {
+        let candidates = self.candidates(hidapi, config)?;
 
-        match candidates.next() {
-            None => Err(MatchError::NoDevices.into()),
-            Some((dev, risks)) => {
-                if candidates.next().is_some() {
-                    return Err(MatchError::MultipleDevices.into());
-                }
+        let (dev, risks) = match candidates.as_slice() {
+            [] => return Err(MatchError::NoDevices.into()),
+            [only] => *only,
+            _ if std::io::stdin().is_terminal() => pick_device(hidapi, config, &candidates)?,
+            _ => {
+                let devices: Vec<_> = candidates.iter().map(|(d, _)| *d).collect();
+                return Err(MatchError::MultipleDevices(describe_candidates(
+                    hidapi, config, &devices,
+                ))
+                .into());
+            }
+        };
 
-                if risks.untested {
-                    warn!(
-                        "Device has not been tested with bose-dfu; by proceeding, you risk damaging it"
-                    );
-                }
+        self.check_risks(risks)?;
 
-                if risks.ambiguous_mode {
-                    warn!(
-                        "Cannot determine device's mode; command may damage devices not in {} mode",
-                        self.required_mode.unwrap()
-                    );
-                }
+        dev.open_device(hidapi)
+            .map(|open| (open, dev))
+            .context("failed to open device; do you have permission?")
+    }
 
-                if (risks.untested || risks.ambiguous_mode) && !self.force {
-                    bail!("to use an untested or ambiguous-mode device, you must pass -f");
-                }
+    /// Like [DeviceSpec::get_device], but return every matching device instead of requiring there
+    /// to be exactly one.
+    fn get_devices<'a>(
+        &self,
+        hidapi: &'a HidApi,
+        config: &Config,
+    ) -> Result<Vec<(HidDevice, &'a DeviceInfo)>> {
+        let candidates = self.candidates(hidapi, config)?;
+        if candidates.is_empty() {
+            return Err(MatchError::NoDevices.into());
+        }
+
+        for &(dev, risks) in &candidates {
+            self.check_risks(risks).with_context(|| {
+                format!(
+                    "can't use device {}",
+                    dev.serial_number().unwrap_or("INVALID")
+                )
+            })?;
+        }
 
+        candidates
+            .into_iter()
+            .map(|(dev, _)| {
                 dev.open_device(hidapi)
                     .map(|open| (open, dev))
                     .context("failed to open device; do you have permission?")
+            })
+            .collect()
+    }
+
+    /// Find all devices matching this specification.
+    fn candidates<'a>(
+        &self,
+        hidapi: &'a HidApi,
+        config: &Config,
+    ) -> Result<Vec<(&'a DeviceInfo, DeviceRisks)>> {
+        self.check(config)?;
+        Ok(hidapi
+            .device_list()
+            .filter_map(|d| self.match_dev(hidapi, config, d).map(|r| (d, r)))
+            .collect())
+    }
+
+    /// Refuse specifications that can't match any device, so the user gets told why rather than
+    /// just that nothing matched.
+    fn check(&self, config: &Config) -> Result<(), MatchError> {
+        if let Some(ref name) = self.name
+            && config.serial_for(name).is_none()
+        {
+            return Err(MatchError::UnknownNickname(name.clone()));
+        }
+
+        if self.hw_serial.is_some() && self.required_mode == Some(DeviceMode::Dfu) {
+            return Err(MatchError::HwSerialInDfuMode);
+        }
+
+        Ok(())
+    }
+
+    /// Warn about any risks of using a matched device, and return an error if those risks need to
+    /// be acknowledged with `--force` and haven't been.
+    fn check_risks(&self, risks: DeviceRisks) -> Result<()> {
+        if risks.untested {
+            warn!("Device has not been tested with bose-dfu; by proceeding, you risk damaging it");
+        }
+
+        if risks.ambiguous_mode {
+            warn!(
+                "Cannot determine device's mode; command may damage devices not in {} mode",
+                self.required_mode.unwrap()
+            );
+        }
+
+        if (risks.untested || risks.ambiguous_mode) && !self.force {
+            bail!("to use an untested or ambiguous-mode device, you must pass -f");
+        }
+
+        Ok(())
+    }
+}
+
+/// Ask the user to choose one of several matching devices. Only call this when stdin is a TTY.
+fn pick_device<'a>(
+    hidapi: &HidApi,
+    config: &Config,
+    candidates: &[(&'a DeviceInfo, DeviceRisks)],
+) -> Result<(&'a DeviceInfo, DeviceRisks)> {
+    println!("Multiple devices match specification:");
+    for (i, (dev, _)) in candidates.iter().enumerate() {
+        println!("  {}) {}", i + 1, describe_device(hidapi, config, dev));
+    }
+
+    let mut rl = DefaultEditor::new()?;
+    loop {
+        let line = match rl.readline(&format!("Select a device [1-{}]: ", candidates.len())) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted | ReadlineError::Eof) => {
+                bail!("no device selected")
             }
+            Err(err) => return Err(err.into()),
+        };
+
+        match line.trim().parse::<usize>() {
+            Ok(i) if (1..=candidates.len()).contains(&i) => return Ok(candidates[i - 1]),
+            _ => println!("Please enter a number between 1 and {}", candidates.len()),
         }
     }
 }
 
+/// Build a human-readable list of devices, each annotated with the arguments that would select
+/// only it, for use in error messages.
+fn describe_candidates(hidapi: &HidApi, config: &Config, devices: &[&DeviceInfo]) -> String {
+    let mut lines = vec![];
+    for dev in devices {
+        let serial = dev.serial_number();
+        let unique_serial = serial.is_some()
+            && devices
+                .iter()
+                .filter(|d| d.serial_number() == serial)
+                .count()
+                == 1;
+        let unique_pid = devices
+            .iter()
+            .filter(|d| d.product_id() == dev.product_id())
+            .count()
+            == 1;
+
+        let selector = match (unique_serial, unique_pid) {
+            (true, _) => match config.nickname_for(serial.unwrap()) {
+                Some(name) => format!("-n {name}"),
+                None => format!("-s {}", serial.unwrap()),
+            },
+            (false, true) => format!("-p {:04x}", dev.product_id()),
+            // Paths are unique by definition, but they're unwieldy, so only suggest them last.
+            (false, false) => format!("--path {}", dev.path().to_string_lossy()),
+        };
+
+        lines.push(format!(
+            "  {} ({selector})",
+            describe_device(hidapi, config, dev)
+        ));
+    }
+    lines.join("\n")
+}
+
+/// Summarize a device on one line, in the format used by the `list` subcommand.
+fn summarize_device(config: &Config, dev: &DeviceInfo) -> String {
+    let dev_id = UsbId {
+        vid: dev.vendor_id(),
+        pid: dev.product_id(),
+    };
+
+    let mut desc = format!(
+        "{} {} {} [{}]",
+        dev_id,
+        dev.serial_number().unwrap_or("INVALID"),
+        dev.product_string().unwrap_or("INVALID"),
+        identify_device(dev_id, dev.usage_page()),
+    );
+
+    if let Some(name) = dev.serial_number().and_then(|s| config.nickname_for(s)) {
+        let _ = write!(desc, " nickname: {name}");
+    }
+
+    desc
+}
+
+/// Like [summarize_device], but if the device is a compatible device in normal mode, also try to
+/// read its model over TAP.
+fn describe_device(hidapi: &HidApi, config: &Config, dev: &DeviceInfo) -> String {
+    let mut desc = summarize_device(config, dev);
+
+    let dev_id = UsbId {
+        vid: dev.vendor_id(),
+        pid: dev.product_id(),
+    };
+
+    // Only talk to devices we know speak TAP, since sending it to anything else could be harmful.
+    if let DeviceCompat::Compatible(DeviceMode::Normal) = identify_device(dev_id, dev.usage_page())
+        && let Ok(open) = dev.open_device(hidapi)
+        && let Ok(model) = read_info_field(&open, InfoField::DeviceModel)
+    {
+        let _ = write!(desc, " model: {model}");
+    }
+
+    desc
+}
+
 #[derive(Copy, Clone, Debug)]
 struct DeviceRisks {
     /// The device has not been tested, and bose-dfu might brick it.
@@ -357,6 +3258,155 @@ enum MatchError {
     #[error("no devices match specification")]
     NoDevices,
 
-    #[error("multiple devices match specification")]
-    MultipleDevices,
+    #[error("multiple devices match specification; narrow it down with one of:\n{0}")]
+    MultipleDevices(String),
+
+    #[error("no device has the nickname {0:?} in the configuration file")]
+    UnknownNickname(String),
+
+    #[error(
+        "--hw-serial can't select a device in DFU mode, since only the normal firmware can report \
+        its hardware serial number; select it by USB serial number (-s) or product ID (-p) instead"
+    )]
+    HwSerialInDfuMode,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bad_crc_download_records_variant() {
+        let data = std::fs::read("test_data/dfu/bad_crc.dfu").unwrap();
+        let file: FirmwareFile = DfuFile::new(Cursor::new(data)).unwrap();
+        let id = UsbId {
+            vid: 0xdead,
+            pid: 0xbeef,
+        };
+
+        // The checks download_cmd() runs before touching the device.
+        let result = check_firmware(&file, id, false);
+        let auditor = Auditor {
+            log: None,
+            user: "test".to_owned(),
+        };
+        let entry = auditor.entry("download", vec![], &result);
+        assert_eq!(entry.result, Outcome::Failed);
+        assert_eq!(
+            entry.error_variant.as_deref(),
+            Some("dfu_file::SuffixError::BadCRC")
+        );
+
+        let result = Err(anyhow!("something else went wrong"));
+        let entry = auditor.entry("download", vec![], &result);
+        assert_eq!(entry.error_variant.as_deref(), Some(OTHER_ERROR_VARIANT));
+    }
+
+    #[test]
+    fn rollback_prefers_unchanged_history_file() {
+        let dir =
+            std::env::temp_dir().join(format!("bose-dfu-main-{}-rollback", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let library_copy = dir.join("correct.dfu");
+        std::fs::copy("test_data/dfu/correct.dfu", &library_copy).unwrap();
+        let config = Config {
+            firmware_library: Some(dir.clone()),
+            ..Config::default()
+        };
+
+        let id = UsbId {
+            vid: 0xdead,
+            pid: 0xbeef,
+        };
+        let target = FirmwareTarget {
+            dfu_id: id,
+            model: None,
+        };
+        // The version in correct.dfu's release number.
+        let version: FirmwareVersion = "4.0.2".parse().unwrap();
+        let installed = std::path::absolute("test_data/dfu/correct.dfu").unwrap();
+        let sha256 = sha256_hex(&std::fs::read(&installed).unwrap());
+        let mut history = History::default();
+        history.entries.push(HistoryEntry::new(
+            Some("A"),
+            id,
+            None,
+            Some(&version),
+            &sha256,
+            Some(&installed),
+        ));
+
+        let (path, firmware) =
+            find_rollback_firmware(&config, &history, &target, &version).unwrap();
+        assert_eq!(path, installed);
+        assert_eq!(firmware.sha256, sha256);
+
+        // A file that changed since it was installed is skipped in favor of the library.
+        history.entries[0].sha256 = "0".repeat(64);
+        let (path, _) = find_rollback_firmware(&config, &history, &target, &version).unwrap();
+        assert_eq!(path, library_copy);
+
+        let missing: FirmwareVersion = "9.9.9".parse().unwrap();
+        assert!(find_rollback_firmware(&config, &history, &target, &missing).is_err());
+    }
+
+    #[test]
+    fn version_checks_ignore_missing_build() {
+        let checks = VersionChecks {
+            allow_downgrade: false,
+            reinstall: false,
+        };
+        let file_version: FirmwareVersion = "1.3.8".parse().unwrap();
+        // Not a downgrade, even though the file's version has no build number.
+        let err = checks
+            .check("1.3.8-3466+09c2375", &file_version)
+            .unwrap_err();
+        assert!(err.to_string().contains("already running"), "{err:#}");
+        assert!(checks.check("1.3.7-3466", &file_version).is_ok());
+        assert!(checks.check("1.3.9", &file_version).is_err());
+
+        let reinstall = VersionChecks {
+            reinstall: true,
+            ..checks
+        };
+        assert!(reinstall.check("1.3.8-3466", &file_version).is_ok());
+    }
+
+    #[test]
+    fn readback_is_checked_against_payload() {
+        let payload = [1u8, 2, 3, 4];
+        assert!(check_readback(ReadbackRule::Exact, &payload, &payload).is_ok());
+
+        let err = check_readback(ReadbackRule::Exact, &payload, &[1, 2]).unwrap_err();
+        assert!(err.to_string().contains("only 2 bytes"), "{err:#}");
+
+        let err = check_readback(ReadbackRule::Exact, &payload, &[1, 2, 3, 5]).unwrap_err();
+        assert!(err.to_string().contains("1 of 1 blocks"), "{err:#}");
+    }
+
+    #[test]
+    fn hw_serial_is_refused_in_dfu_mode() {
+        let spec = DeviceSpec {
+            serial: None,
+            pid: None,
+            name: None,
+            hw_serial: Some("069420".to_owned()),
+            product: None,
+            path: None,
+            force: false,
+            required_mode: Some(DeviceMode::Dfu),
+        };
+        let config = Config::default();
+        assert!(matches!(
+            spec.check(&config),
+            Err(MatchError::HwSerialInDfuMode)
+        ));
+
+        let normal = DeviceSpec {
+            required_mode: Some(DeviceMode::Normal),
+            ..spec
+        };
+        assert!(normal.check(&config).is_ok());
+    }
 }
@@ -0,0 +1,112 @@
+use crate::device_ids::UsbId;
+use crate::dfu_file::SuffixInfo;
+use crate::version::FirmwareVersion;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// A list of known-good firmware images and their SHA-256 hashes, read from a TOML file containing
+/// a list of `[[image]]` tables.
+///
+/// A DFU file's CRC only protects against accidental corruption, so checking a file's hash against
+/// a trusted manifest is the only way to tell an official image from a modified or mislabeled one.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    #[serde(default, rename = "image")]
+    pub images: Vec<ManifestEntry>,
+}
+
+/// One known-good firmware image.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestEntry {
+    /// Human-readable name of the model the image is for.
+    pub model: String,
+    /// DFU-mode USB ID of the model the image is for. If set, files claiming to be this image
+    /// but having a different hash are reported as mismatches rather than unknown.
+    pub usb_id: Option<UsbId>,
+    pub version: FirmwareVersion,
+    /// SHA-256 hash of the whole file, as a hex string.
+    pub sha256: String,
+}
+
+/// What a manifest says about a firmware file.
+#[derive(Debug)]
+pub enum Verdict<'a> {
+    /// The file's hash is listed in the manifest.
+    Known(&'a ManifestEntry),
+    /// The file's hash isn't listed, and no listed image has the same USB ID and version.
+    Unknown,
+    /// The file's hash isn't listed, but it claims to be this listed image.
+    Mismatch(&'a ManifestEntry),
+}
+
+impl Manifest {
+    /// Load a manifest from the TOML file at `path`.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let text = std::fs::read_to_string(path).map_err(|source| Error::IoError {
+            source,
+            path: path.to_owned(),
+        })?;
+
+        let mut manifest: Manifest = toml::from_str(&text).map_err(|source| Error::ParseError {
+            source,
+            path: path.to_owned(),
+        })?;
+
+        for (i, image) in manifest.images.iter_mut().enumerate() {
+            if image.sha256.len() != 64 || !image.sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(Error::BadHash(i));
+            }
+            image.sha256.make_ascii_lowercase();
+        }
+
+        Ok(manifest)
+    }
+
+    /// Check a file with the given SHA-256 hash (as a hex string) and suffix against the manifest.
+    pub fn check(&self, sha256: &str, suffix: &SuffixInfo) -> Verdict<'_> {
+        if let Some(known) = self
+            .images
+            .iter()
+            .find(|i| i.sha256.eq_ignore_ascii_case(sha256))
+        {
+            return Verdict::Known(known);
+        }
+
+        let Some(version) = suffix.firmware_version() else {
+            return Verdict::Unknown;
+        };
+        let claimed = self.images.iter().find(|i| {
+            i.usb_id.is_some_and(|id| {
+                suffix.vendor_id.0 == Some(id.vid) && suffix.product_id.0 == Some(id.pid)
+            }) && i.version.is_same_release(&version)
+        });
+
+        match claimed {
+            Some(image) => Verdict::Mismatch(image),
+            None => Verdict::Unknown,
+        }
+    }
+}
+
+/// Errors that can happen while loading a manifest file.
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("failed to read manifest file {}", .path.display())]
+    IoError {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+
+    #[error("invalid manifest file {}", .path.display())]
+    ParseError {
+        source: toml::de::Error,
+        path: PathBuf,
+    },
+
+    #[error("manifest entry {} has an invalid SHA-256 hash", .0 + 1)]
+    BadHash(usize),
+}
@@ -0,0 +1,102 @@
+use crate::device_ids::{UsbId, find_device_ids};
+use crate::version::FirmwareVersion;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// A description of which firmware each kind of device should be running, read from a TOML file
+/// containing a list of `[[firmware]]` tables.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default, rename = "firmware")]
+    pub entries: Vec<PolicyEntry>,
+}
+
+/// Which firmware devices of one kind should be running. At least one of `model` and `usb_id` must
+/// be set; if both are, a device must match both.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyEntry {
+    /// Model string reported by the device's normal firmware.
+    pub model: Option<String>,
+    /// USB ID of the device in either normal or DFU mode.
+    pub usb_id: Option<UsbId>,
+    /// Firmware file to install. Relative paths are relative to the policy file.
+    pub file: PathBuf,
+    /// Version the device reports when running the firmware in `file`. If unset, the version is
+    /// taken from the file's release number.
+    pub version: Option<FirmwareVersion>,
+}
+
+impl Policy {
+    /// Load a policy from the TOML file at `path`, resolving firmware paths relative to it.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let text = std::fs::read_to_string(path).map_err(|source| Error::IoError {
+            source,
+            path: path.to_owned(),
+        })?;
+
+        let mut policy: Policy = toml::from_str(&text).map_err(|source| Error::ParseError {
+            source,
+            path: path.to_owned(),
+        })?;
+
+        // Never leave a path as `-`, which would mean standard input.
+        let base = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        for (i, entry) in policy.entries.iter_mut().enumerate() {
+            if entry.model.is_none() && entry.usb_id.is_none() {
+                return Err(Error::NoCriteria(i));
+            }
+            entry.file = base.join(&entry.file);
+        }
+
+        Ok(policy)
+    }
+
+    /// Find the first entry that applies to a device with the given model string and USB ID.
+    pub fn find(&self, model: &str, id: UsbId) -> Option<&PolicyEntry> {
+        self.entries.iter().find(|e| e.matches(model, id))
+    }
+}
+
+impl PolicyEntry {
+    /// Check if this entry applies to a device with the given model string and USB ID.
+    pub fn matches(&self, model: &str, id: UsbId) -> bool {
+        if let Some(ref m) = self.model
+            && m != model
+        {
+            return false;
+        }
+
+        match self.usb_id {
+            None => true,
+            Some(want) if want == id => true,
+            // The entry might name the device's ID in its other mode.
+            Some(want) => find_device_ids(id).is_some_and(|ids| ids.match_id(want).is_some()),
+        }
+    }
+}
+
+/// Errors that can happen while loading a policy file.
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("failed to read policy file {}", .path.display())]
+    IoError {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+
+    #[error("invalid policy file {}", .path.display())]
+    ParseError {
+        source: toml::de::Error,
+        path: PathBuf,
+    },
+
+    #[error("policy entry {} has neither a model nor a USB ID", .0 + 1)]
+    NoCriteria(usize),
+}
@@ -9,13 +9,25 @@ use std::time::Duration;
 use thiserror::Error;
 
 const XFER_HEADER_SIZE: usize = 5;
-// Gathered from USB captures. Probably corresponds to a 1024-byte internal buffer in the firmware.
-const XFER_DATA_SIZE: usize = 1017;
+/// Number of payload bytes sent to or received from the device in each block. Gathered from USB
+/// captures. Probably corresponds to a 1024-byte internal buffer in the firmware.
+pub const XFER_DATA_SIZE: usize = 1017;
 
 /// Download (i.e. write firmware to) the device. `device` must be in DFU mode. `file` should
 /// contain only the firmware payload to be written, with any DFU header stripped off.
 pub fn download(device: &HidDevice, file: &mut impl Read) -> Result<(), Error> {
+    download_with_progress(device, file, |_| {})
+}
+
+/// Like [download], but call `progress` with the total number of payload bytes the device has
+/// accepted so far after each block is written.
+pub fn download_with_progress(
+    device: &HidDevice,
+    file: &mut impl Read,
+    mut progress: impl FnMut(u64),
+) -> Result<(), Error> {
     let mut report = vec![];
+    let mut written = 0u64;
 
     let mut block_num = 0u16;
     let mut prev_delay = Duration::from_millis(0);
@@ -66,6 +78,9 @@ pub fn download(device: &HidDevice, file: &mut impl Read) -> Result<(), Error> {
 
         trace!("Successfully downloaded block {block_num:#06x} ({data_size} bytes)");
 
+        written += data_size as u64;
+        progress(written);
+
         if data_size == 0 {
             // Empty read means we're done, device should now be idle.
             status.ensure_state(DfuState::dfuIDLE)?;
@@ -217,6 +232,12 @@ pub fn leave_dfu(device: &HidDevice) -> Result<(), Error> {
         })
 }
 
+/// Query the status of a device in DFU mode, without trying to change its state. `device` must be
+/// in DFU mode.
+pub fn read_status(device: &HidDevice) -> Result<DfuStatusResult, Error> {
+    DfuStatusResult::read_from_device(device)
+}
+
 /// Attempt to transition the device to the [dfuIDLE](DfuState::dfuIDLE) state. If we can't or
 /// don't know how to, return an error. `device` must be in DFU mode.
 pub fn ensure_idle(device: &HidDevice) -> Result<(), Error> {
@@ -396,8 +417,9 @@ enum DfuRequest {
     BOSE_EXIT_DFU = 0xff, // Custom, not from DFU spec
 }
 
+/// The response to a DFU_GETSTATUS request.
 #[derive(Copy, Clone, Debug)]
-struct DfuStatusResult {
+pub struct DfuStatusResult {
     pub status: DfuStatus,
     pub state: DfuState,
     pub poll_timeout: u32,
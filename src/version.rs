@@ -0,0 +1,242 @@
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::fmt::Display;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// A firmware version, in the format Bose's normal firmware reports it (e.g. "1.3.8-3466+09c2375":
+/// a dotted version number, an optional build number, and optional metadata).
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct FirmwareVersion {
+    pub numbers: Vec<u32>,
+    pub build: Option<u32>,
+    /// Anything after the version and build number, such as "+09c2375". Ignored when comparing
+    /// versions.
+    pub metadata: Option<String>,
+}
+
+impl FirmwareVersion {
+    /// Convert from the binary-coded decimal release number (bcdDevice) in a DFU suffix. USB
+    /// encodes versions in BCD as 0xJJMN for version JJ.M.N (see bcdUSB in section 9.6.1 of the USB
+    /// 2.0 specification), so e.g. 0x0138 is 1.3.8. Bose doesn't document how its release numbers
+    /// relate to its version strings, so this assumes they follow the same convention. Returns
+    /// [None] if `bcd` isn't valid BCD.
+    pub fn from_bcd(bcd: u16) -> Option<Self> {
+        let digit = |shift: u16| -> Option<u32> {
+            let d = ((bcd >> shift) & 0xf) as u32;
+            (d < 10).then_some(d)
+        };
+
+        Some(Self {
+            numbers: vec![digit(12)? * 10 + digit(8)?, digit(4)?, digit(0)?],
+            build: None,
+            metadata: None,
+        })
+    }
+
+    /// Convert to a binary-coded decimal release number for a DFU suffix, if this version can be
+    /// represented as one.
+    pub fn to_bcd(&self) -> Option<u16> {
+        let (major, minor, patch) = match self.numbers[..] {
+            [major] => (major, 0, 0),
+            [major, minor] => (major, minor, 0),
+            [major, minor, patch] => (major, minor, patch),
+            _ => return None,
+        };
+
+        if major > 99 || minor > 9 || patch > 9 {
+            return None;
+        }
+
+        Some((((major / 10) << 12) | ((major % 10) << 8) | (minor << 4) | patch) as u16)
+    }
+
+    /// Compare two versions. Missing trailing version numbers count as zero, and a version without
+    /// a build number sorts before the same version with one, so "1.3.8" < "1.3.8.0-3466" <
+    /// "1.3.8-3467". This is a total order, so it can be used for sorting; use
+    /// [FirmwareVersion::is_same_release] to check whether two versions are the same.
+    pub fn compare(&self, other: &Self) -> Ordering {
+        self.compare_numbers(other)
+            .then(self.build.cmp(&other.build))
+    }
+
+    /// Whether two versions are the same release. Build numbers are only compared if both versions
+    /// have one, since a DFU suffix's release number can't hold one: "1.3.8" is the same release as
+    /// "1.3.8.0-3466", but "1.3.8-3466" isn't the same as "1.3.8-3467".
+    pub fn is_same_release(&self, other: &Self) -> bool {
+        let builds_match = match (self.build, other.build) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        };
+        self.compare_numbers(other).is_eq() && builds_match
+    }
+
+    fn compare_numbers(&self, other: &Self) -> Ordering {
+        let len = self.numbers.len().max(other.numbers.len());
+        let at = |v: &Self, i| v.numbers.get(i).copied().unwrap_or(0);
+        (0..len)
+            .map(|i| at(self, i).cmp(&at(other, i)))
+            .find(|o| o.is_ne())
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+impl FromStr for FirmwareVersion {
+    type Err = ParseVersionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseVersionError(s.to_owned());
+
+        let s = s.trim();
+        let version_end = s
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(s.len());
+        let (version, mut rest) = s.split_at(version_end);
+
+        let numbers = version
+            .split('.')
+            .map(|n| n.parse::<u32>().map_err(|_| err()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut build = None;
+        if let Some(after_dash) = rest.strip_prefix('-') {
+            let build_end = after_dash
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(after_dash.len());
+            if build_end > 0 {
+                build = Some(after_dash[..build_end].parse().map_err(|_| err())?);
+                rest = &after_dash[build_end..];
+            }
+        }
+
+        Ok(Self {
+            numbers,
+            build,
+            metadata: (!rest.is_empty()).then(|| rest.to_owned()),
+        })
+    }
+}
+
+impl TryFrom<String> for FirmwareVersion {
+    type Error = ParseVersionError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl Display for FirmwareVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (i, n) in self.numbers.iter().enumerate() {
+            if i != 0 {
+                f.write_str(".")?;
+            }
+            write!(f, "{n}")?;
+        }
+
+        if let Some(build) = self.build {
+            write!(f, "-{build}")?;
+        }
+
+        if let Some(ref metadata) = self.metadata {
+            f.write_str(metadata)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Error returned when a string doesn't start with a dotted version number.
+#[derive(Error, Debug)]
+#[error("can't parse firmware version {0:?}")]
+pub struct ParseVersionError(String);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(s: &str) -> FirmwareVersion {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_version_strings() {
+        let version = v("1.3.8-3466+09c2375");
+        assert_eq!(version.numbers, [1, 3, 8]);
+        assert_eq!(version.build, Some(3466));
+        assert_eq!(version.metadata.as_deref(), Some("+09c2375"));
+        assert_eq!(version.to_string(), "1.3.8-3466+09c2375");
+
+        let version = v(" 2.0 ");
+        assert_eq!(version.numbers, [2, 0]);
+        assert_eq!(version.build, None);
+        assert_eq!(version.metadata, None);
+
+        // A dash not followed by digits is metadata, not a build number.
+        let version = v("1.3.8-beta");
+        assert_eq!(version.build, None);
+        assert_eq!(version.metadata.as_deref(), Some("-beta"));
+
+        for bad in ["", "abc", "1..2", ".1", "1.", "99999999999"] {
+            assert!(bad.parse::<FirmwareVersion>().is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn bcd_round_trips() {
+        assert_eq!(
+            FirmwareVersion::from_bcd(0x0138).unwrap().to_string(),
+            "1.3.8"
+        );
+        assert_eq!(
+            FirmwareVersion::from_bcd(0x9999).unwrap().to_string(),
+            "99.9.9"
+        );
+        assert!(FirmwareVersion::from_bcd(0x013a).is_none());
+        assert!(FirmwareVersion::from_bcd(0xa000).is_none());
+
+        for bcd in (0..=0xffff).filter_map(|b| FirmwareVersion::from_bcd(b).map(|_| b)) {
+            let version = FirmwareVersion::from_bcd(bcd).unwrap();
+            assert_eq!(version.to_bcd(), Some(bcd));
+        }
+
+        assert_eq!(v("4.0.2").to_bcd(), Some(0x0402));
+        assert_eq!(v("12").to_bcd(), Some(0x1200));
+        assert_eq!(v("1.3.8-3466").to_bcd(), Some(0x0138));
+        assert_eq!(v("100.0.0").to_bcd(), None);
+        assert_eq!(v("1.10.0").to_bcd(), None);
+        assert_eq!(v("1.3.8.1").to_bcd(), None);
+    }
+
+    #[test]
+    fn compares_versions() {
+        assert_eq!(v("1.3.8").compare(&v("1.3.9")), Ordering::Less);
+        assert_eq!(v("1.10").compare(&v("1.9")), Ordering::Greater);
+        assert_eq!(v("1.3.8").compare(&v("1.3.8.0")), Ordering::Equal);
+        assert_eq!(v("1.3.8-2").compare(&v("1.3.8-10")), Ordering::Less);
+        assert_eq!(v("1.3.8+a").compare(&v("1.3.8+b")), Ordering::Equal);
+        assert_eq!(v("1.3.9").compare(&v("1.3.8-9999")), Ordering::Greater);
+    }
+
+    #[test]
+    fn missing_build_sorts_lowest() {
+        let (a, b, c) = (v("1.3.8-3466"), v("1.3.8"), v("1.3.8-3467"));
+        assert_eq!(b.compare(&a), Ordering::Less);
+        assert_eq!(a.compare(&c), Ordering::Less);
+        assert_eq!(b.compare(&c), Ordering::Less);
+
+        let mut versions = [c, b, a];
+        versions.sort_by(FirmwareVersion::compare);
+        let sorted: Vec<_> = versions.iter().map(ToString::to_string).collect();
+        assert_eq!(sorted, ["1.3.8", "1.3.8-3466", "1.3.8-3467"]);
+    }
+
+    #[test]
+    fn same_release_ignores_missing_builds() {
+        assert!(v("1.3.8").is_same_release(&v("1.3.8.0-3466")));
+        assert!(v("1.3.8-3466").is_same_release(&v("1.3.8-3466+09c2375")));
+        assert!(!v("1.3.8-3466").is_same_release(&v("1.3.8-3467")));
+        assert!(!v("1.3.8").is_same_release(&v("1.3.9")));
+    }
+}